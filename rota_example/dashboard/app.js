@@ -0,0 +1,207 @@
+// rota dashboard. Signs in with an admin token, kept for the browser session only, and calls the admin routes with it.
+"use strict";
+
+const MONTHS = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+function token() {
+  return sessionStorage.getItem("rota-admin-token");
+}
+
+function showMessage(text, isError) {
+  const message = document.getElementById("message");
+  message.textContent = text;
+  message.className = isError ? "error" : "";
+}
+
+// Calls an admin route. Admin routes take their arguments as headers.
+async function call(method, path, headers, body) {
+  const response = await fetch(path, {
+    method,
+    headers: Object.assign({ "Authorization": "Bearer " + token() }, headers || {}),
+    body,
+  });
+  if (response.status === 401) {
+    signOut();
+    throw new Error("Signed out, the admin token was not accepted.");
+  }
+  if (!response.ok) {
+    throw new Error(path + " failed with " + response.status + ": " + (await response.text()));
+  }
+  return response;
+}
+
+function cell(row, content) {
+  const td = document.createElement("td");
+  if (content instanceof Node) {
+    td.appendChild(content);
+  } else {
+    td.textContent = content == null ? "" : content;
+  }
+  row.appendChild(td);
+  return td;
+}
+
+function input(value) {
+  const field = document.createElement("input");
+  field.value = value;
+  return field;
+}
+
+function formatDate(value) {
+  return value ? new Date(value).toISOString().replace("T", " ").replace(/\.\d+Z$/, "Z") : "";
+}
+
+// Formats a date the way rota stores compile times in `.ct` files, like the __DATE__ and __TIME__ macros of the
+// firmware: `"Oct  8 2026       12 00 00`.
+function compileTime(date) {
+  const pad = (n) => String(n).padStart(2, "0");
+  return "\"" + MONTHS[date.getUTCMonth()] + " " + String(date.getUTCDate()).padStart(2, " ") + " " + date.getUTCFullYear()
+    + "       " + pad(date.getUTCHours()) + " " + pad(date.getUTCMinutes()) + " " + pad(date.getUTCSeconds());
+}
+
+// Saves the fields of a device row that were changed.
+async function saveDevice(device, alias, group, target) {
+  const id = { "esp-device-id": device.mac };
+  if (alias !== device.alias) {
+    await call("POST", "/assignalias", Object.assign({ "esp-alias": alias }, id));
+  }
+  if (group !== device.group) {
+    await call("POST", "/assigngroup", Object.assign({ "esp-group": group }, id));
+  }
+  if (target !== device.target) {
+    await call("POST", "/assignfirmware", Object.assign({ "esp-target-firmware": target }, id));
+  }
+}
+
+function renderDevices(devices) {
+  const body = document.getElementById("devices");
+  body.replaceChildren();
+  for (const device of devices) {
+    const row = document.createElement("tr");
+    const alias = input(device.alias);
+    const group = input(device.group);
+    const target = input(device.target);
+    cell(row, device.mac);
+    cell(row, alias);
+    cell(row, group);
+    cell(row, target);
+    cell(row, formatDate(device.reported_version));
+    cell(row, formatDate(device.latest_version));
+    cell(row, formatDate(device.last_seen));
+    cell(row, device.status ? device.status.replace(/_/g, " ") : "never seen").className = "status-" + (device.status || "none");
+    cell(row, device.cohort);
+    const save = document.createElement("button");
+    save.textContent = "Save";
+    save.addEventListener("click", () => run(async () => {
+      await saveDevice(device, alias.value, group.value, target.value);
+      showMessage("Saved " + device.mac + ".");
+    }));
+    cell(row, save);
+    body.appendChild(row);
+  }
+}
+
+function renderRollouts(rollouts) {
+  const body = document.getElementById("rollouts");
+  body.replaceChildren();
+  for (const rollout of rollouts) {
+    const row = document.createElement("tr");
+    cell(row, rollout.target);
+    cell(row, rollout.percent + "%");
+    cell(row, rollout.paused ? "yes" : "no");
+    cell(row, formatDate(rollout.updated));
+    row.addEventListener("click", () => {
+      const form = document.getElementById("rollout-form");
+      form.firmware.value = rollout.target;
+      form.percent.value = rollout.percent;
+      form.paused.checked = rollout.paused;
+    });
+    body.appendChild(row);
+  }
+}
+
+async function refresh() {
+  const [devices, rollouts] = await Promise.all([
+    call("GET", "/fleet").then((r) => r.json()),
+    call("GET", "/rollouts").then((r) => r.json()),
+  ]);
+  renderDevices(devices);
+  renderRollouts(rollouts);
+}
+
+// Runs an action, reports its error and reloads the tables afterwards.
+async function run(action) {
+  try {
+    await action();
+    await refresh();
+  } catch (e) {
+    showMessage(e.message, true);
+  }
+}
+
+function showApp(signedIn) {
+  document.getElementById("login").hidden = signedIn;
+  document.getElementById("app").hidden = !signedIn;
+  document.getElementById("logout").hidden = !signedIn;
+}
+
+function signOut() {
+  sessionStorage.removeItem("rota-admin-token");
+  showApp(false);
+}
+
+document.getElementById("login-form").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  sessionStorage.setItem("rota-admin-token", document.getElementById("token").value);
+  document.getElementById("token").value = "";
+  showApp(true);
+  showMessage("");
+  await run(async () => {});
+});
+
+document.getElementById("logout").addEventListener("click", signOut);
+
+document.getElementById("register-form").addEventListener("submit", (event) => {
+  event.preventDefault();
+  const mac = event.target.mac.value.toUpperCase();
+  run(async () => {
+    await call("POST", "/register", { "esp-device-id": mac });
+    event.target.reset();
+    showMessage("Registered " + mac + ".");
+  });
+});
+
+document.getElementById("rollout-form").addEventListener("submit", (event) => {
+  event.preventDefault();
+  const form = event.target;
+  const target = form.firmware.value;
+  run(async () => {
+    await call("POST", "/rollout", {
+      "esp-target-firmware": target,
+      "esp-rollout-percent": form.percent.value,
+      "esp-rollout-paused": String(form.paused.checked),
+    });
+    showMessage("Saved rollout of " + target + ".");
+  });
+});
+
+document.getElementById("upload-form").addEventListener("submit", (event) => {
+  event.preventDefault();
+  const form = event.target;
+  const target = form.firmware.value;
+  run(async () => {
+    const binary = await form.binary.files[0].arrayBuffer();
+    await call("POST", "/uploadfirmware", {
+      "esp-target-firmware": target,
+      "esp-compile-time": compileTime(new Date(form.compiled.value + "Z")),
+      "content-type": "application/octet-stream",
+    }, binary);
+    form.reset();
+    showMessage("Uploaded firmware for " + target + ".");
+  });
+});
+
+if (token()) {
+  showApp(true);
+  run(async () => {});
+}
@@ -0,0 +1,191 @@
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::http::HeaderMap;
+use actix_web::Error;
+use futures::future::{ok, Either, Ready};
+use std::task::{Context, Poll};
+use subtle::ConstantTimeEq;
+
+use crate::get_config_path;
+use crate::proxy::ClientInfo;
+use crate::ratelimit::{self, ClientKind};
+use crate::securitylog;
+use tracing::{error, warn};
+
+// The permissions an admin token can be granted in the `admin_tokens` file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdminScope {
+    ReadOnly,
+    FleetOperator,
+    FirmwarePublisher,
+}
+
+impl AdminScope {
+    // This function parses a scope name as written in the `admin_tokens` file.
+    pub fn parse(name: &str) -> Option<AdminScope> {
+        match name {
+            "read-only" => Some(AdminScope::ReadOnly),
+            "fleet-operator" => Some(AdminScope::FleetOperator),
+            "firmware-publisher" => Some(AdminScope::FirmwarePublisher),
+            _ => None,
+        }
+    }
+}
+
+pub struct AdminToken {
+    pub token: String,
+    pub scopes: Vec<AdminScope>,
+}
+
+impl AdminToken {
+    // This function checks whether the token may be used for a route requiring `scope`. Every token can read.
+    pub fn allows(&self, scope: AdminScope) -> bool {
+        scope == AdminScope::ReadOnly || self.scopes.contains(&scope)
+    }
+}
+
+// This function loads the admin tokens file. Each line holds a token followed by a comma separated list of scopes, e.g.
+// `s3cr3t fleet-operator,firmware-publisher`. Blank lines and lines starting with `#` are ignored.
+pub fn load_admin_tokens() -> std::io::Result<Vec<AdminToken>> {
+    let file = std::fs::read_to_string(std::path::Path::new(format!("{}{}", get_config_path(), "admin_tokens").as_str()))?;
+    Ok(parse_admin_tokens(&file))
+}
+
+// This function parses the lines of the admin tokens file. A line naming an unknown scope is rejected as a whole, so a
+// misspelt scope cannot leave a token working with fewer permissions than intended without anyone noticing.
+fn parse_admin_tokens(file: &str) -> Vec<AdminToken> {
+    let mut tokens: Vec<AdminToken> = vec!();
+    for (number, line) in file.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let mut fields = line.split_whitespace();
+        let token = match fields.next() {
+            Some(token) => token.to_string(),
+            _ => continue,
+        };
+        let names = fields.next().unwrap_or("read-only");
+        match names.split(',').map(|name| AdminScope::parse(name).ok_or(name)).collect() {
+            Ok(scopes) => tokens.push(AdminToken { token, scopes }),
+            Err(scope) => error!(line = number + 1, scope, "Ignoring admin token with an unknown scope in admin_tokens."),
+        }
+    }
+    tokens
+}
+
+// This function extracts the token from an `Authorization: Bearer <token>` header.
+pub fn extract_bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("authorization")?.to_str().ok()?;
+    let mut parts = value.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim().to_string()),
+        _ => None,
+    }
+}
+
+// Middleware guarding admin routes. Requests must present an admin token holding the required scope.
+pub struct AdminAuth {
+    scope: AdminScope,
+}
+
+impl AdminAuth {
+    pub fn require(scope: AdminScope) -> AdminAuth {
+        AdminAuth { scope }
+    }
+}
+
+impl<S, B> Transform<S> for AdminAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AdminAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AdminAuthMiddleware { service, scope: self.scope })
+    }
+}
+
+pub struct AdminAuthMiddleware<S> {
+    service: S,
+    scope: AdminScope,
+}
+
+impl<S, B> Service for AdminAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let client = ClientInfo::of_service_request(&req).ip_string();
+        let clients = [(ClientKind::Admin, client.as_str())];
+        if let Err(refusal) = ratelimit::check(&clients) {
+            securitylog::record(429, refusal.reason(), &client, None, req.path());
+            return Either::Right(ok(req.into_response(refusal.response().into_body())))
+        }
+        let presented = match extract_bearer_token(req.headers()) {
+            Some(token) => token,
+            _ => {
+                warn!(decision = "reject", reason = "missing_admin_token", "Admin request rejected, no bearer token.");
+                securitylog::record(401, "missing_admin_token", &client, None, req.path());
+                ratelimit::record_failure(&clients);
+                return Either::Right(ok(req.error_response(ErrorUnauthorized("Missing admin token."))))
+            }
+        };
+        let tokens = match load_admin_tokens() {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                // A server fault, not a failed login, so it is kept out of the security log.
+                error!(error = %e, "Error reading admin_tokens.");
+                return Either::Right(ok(req.error_response(ErrorInternalServerError("Admin tokens unavailable."))))
+            }
+        };
+        match tokens.iter().find(|t| bool::from(t.token.as_bytes().ct_eq(presented.as_bytes()))) {
+            Some(token) if token.allows(self.scope) => {
+                ratelimit::record_success(&clients);
+                Either::Left(self.service.call(req))
+            }
+            Some(_) => {
+                warn!(decision = "reject", reason = "insufficient_scope", scope = ?self.scope, "Admin token lacks the required scope.");
+                securitylog::record(403, "insufficient_scope", &client, None, req.path());
+                Either::Right(ok(req.error_response(ErrorForbidden("Admin token lacks the required scope."))))
+            }
+            _ => {
+                warn!(decision = "reject", reason = "unknown_admin_token", "Admin request rejected, unknown token.");
+                securitylog::record(401, "unknown_admin_token", &client, None, req.path());
+                ratelimit::record_failure(&clients);
+                Either::Right(ok(req.error_response(ErrorUnauthorized("Unknown admin token."))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_admin_tokens, AdminScope};
+
+    #[test]
+    fn rejects_tokens_with_unknown_scopes() {
+        let tokens = parse_admin_tokens("# comment\nreader\nops fleet-operator,firmware-publisher\ntypo fleet-operater\nempty ,\n");
+        let names: Vec<&str> = tokens.iter().map(|t| t.token.as_str()).collect();
+        assert_eq!(names, vec!("reader", "ops"));
+        assert_eq!(tokens[0].scopes, vec!(AdminScope::ReadOnly));
+        assert_eq!(tokens[1].scopes, vec!(AdminScope::FleetOperator, AdminScope::FirmwarePublisher));
+    }
+}
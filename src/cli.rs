@@ -0,0 +1,237 @@
+use crate::encryption;
+use crate::espota;
+use crate::firmware;
+use crate::get_config_path;
+use crate::keys;
+use crate::signing;
+
+const USAGE: &str = "Usage:
+    rota                                   Start the OTA server.
+    rota keys list                         List device API keys.
+    rota keys create <label> [expire days] Create a device API key.
+    rota keys rotate <id> [overlap days]   Replace a key, keeping the old one valid for the overlap period.
+    rota keys revoke <id>                  Revoke a key immediately.
+    rota signing-key <mac>                 Print the request signing key of a device.
+    rota firmware-key generate <path>      Create a firmware signing key and print its public key.
+    rota firmware-key                      Print the public key of the configured firmware signing key.
+    rota sign-firmware <target>            Sign a firmware binary copied into the configuration directory.
+    rota encryption-key generate <path>    Create a key to store firmware encrypted with.
+    rota encrypt-firmware <target>         Encrypt a firmware binary copied into the configuration directory.
+    rota push-firmware <mac> <ip> [port]   Push the firmware of a device's target to it over espota (ArduinoOTA).";
+
+// This function runs a command line subcommand. Returns `None` when no subcommand was given and the server should start.
+pub fn run(args: &[String]) -> Option<i32> {
+    match args.get(1).map(String::as_str) {
+        None => None,
+        Some("keys") => Some(run_keys(&args[2..])),
+        Some("signing-key") => Some(run_signing_key(args.get(2))),
+        Some("firmware-key") => Some(run_firmware_key(&args[2..])),
+        Some("sign-firmware") => Some(run_sign_firmware(args.get(2))),
+        Some("encryption-key") => Some(run_encryption_key(&args[2..])),
+        Some("encrypt-firmware") => Some(run_encrypt_firmware(args.get(2))),
+        Some("push-firmware") => Some(run_push_firmware(&args[2..])),
+        _ => {
+            eprintln!("{}", USAGE);
+            Some(2)
+        }
+    }
+}
+
+// This function handles the `rota keys` subcommands.
+fn run_keys(args: &[String]) -> i32 {
+    let result = match (args.first().map(String::as_str), args.get(1), args.get(2)) {
+        (Some("list"), _, _) => keys::list_keys().map(|list| {
+            for key in list {
+                println!("{}\t{}\tcreated {}\texpires {}\tlast used {}{}",
+                         key.id, key.label, key.created.to_rfc3339(),
+                         key.expires.map(|e| e.to_rfc3339()).unwrap_or_else(|| String::from("never")),
+                         key.last_used.map(|e| e.to_rfc3339()).unwrap_or_else(|| String::from("never")),
+                         if key.revoked { "\tREVOKED" } else { "" });
+            }
+        }),
+        (Some("create"), Some(label), days) => match parse_days(days) {
+            Ok(days) => keys::create_key(label, days).map(|issued| println!("{}\t{}", issued.id, issued.secret)),
+            Err(e) => Err(e),
+        },
+        (Some("rotate"), Some(id), days) => match parse_days(days) {
+            Ok(days) => keys::rotate_key(id, days.unwrap_or(keys::DEFAULT_ROTATION_OVERLAP_DAYS)).map(|issued| match issued {
+                Some(issued) => println!("{}\t{}", issued.id, issued.secret),
+                _ => eprintln!("No active key with id {}.", id),
+            }),
+            Err(e) => Err(e),
+        },
+        (Some("revoke"), Some(id), _) => keys::revoke_key(id).map(|found| if !found {
+            eprintln!("No key with id {}.", id);
+        }),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2
+        }
+    };
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error updating api_keys.toml, {}", e);
+            1
+        }
+    }
+}
+
+// This function parses an optional day count argument, see `keys::parse_days`.
+fn parse_days(arg: Option<&String>) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+    match arg {
+        Some(days) => Ok(Some(keys::parse_days(days)?)),
+        _ => Ok(None),
+    }
+}
+
+// This function prints the request signing key derived for a device.
+fn run_signing_key(mac: Option<&String>) -> i32 {
+    match mac.map(|mac| signing::device_signing_key(mac)) {
+        Some(Some(key)) => {
+            println!("{}", key);
+            0
+        }
+        Some(None) => {
+            eprintln!("Request signing is disabled, set request_signing.master_key in rota.toml.");
+            1
+        }
+        None => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+// This function handles the `rota firmware-key` subcommands.
+fn run_firmware_key(args: &[String]) -> i32 {
+    match (args.first().map(String::as_str), args.get(1)) {
+        (Some("generate"), Some(path)) => match firmware::generate_signing_key(path) {
+            Ok(public_key) => {
+                println!("{}", public_key);
+                0
+            }
+            Err(e) => {
+                eprintln!("Error generating firmware signing key, {}", e);
+                1
+            }
+        },
+        (None, _) => match firmware::public_key() {
+            Some(public_key) => {
+                println!("{}", public_key);
+                0
+            }
+            _ => {
+                eprintln!("Firmware signing is disabled, set firmware_signing.key_path in rota.toml.");
+                1
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+// This function signs the binary of a target with the configured key.
+fn run_sign_firmware(target: Option<&String>) -> i32 {
+    let target = match target {
+        Some(target) => target,
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2
+        }
+    };
+    let target_path = format!("{}{}", get_config_path(), target);
+    let binary = match encryption::read_firmware(&format!("{}.ino.bin", target_path)) {
+        Ok(binary) => binary,
+        Err(e) => {
+            eprintln!("Error reading firmware for target {}, {}", target, e);
+            return 1
+        }
+    };
+    if firmware::public_key().is_none() {
+        eprintln!("Firmware signing is disabled, set firmware_signing.key_path in rota.toml.");
+        return 1
+    }
+    match firmware::signature_for_upload(&binary, None).map(|signature| firmware::save_signature(&target_path, signature.as_ref())) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            eprintln!("Error writing signature for target {}, {}", target, e);
+            1
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// This function handles the `rota encryption-key` subcommands.
+fn run_encryption_key(args: &[String]) -> i32 {
+    match (args.first().map(String::as_str), args.get(1)) {
+        (Some("generate"), Some(path)) => match encryption::generate_key(path) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Error generating encryption key, {}", e);
+                1
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+// This function encrypts the plaintext binary of a target with the configured key.
+fn run_encrypt_firmware(target: Option<&String>) -> i32 {
+    let target = match target {
+        Some(target) => target,
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2
+        }
+    };
+    match encryption::encrypt_in_place(&format!("{}{}.ino.bin", get_config_path(), target)) {
+        Ok(true) => 0,
+        Ok(false) => {
+            eprintln!("Firmware for target {} is already encrypted.", target);
+            0
+        }
+        Err(e) => {
+            eprintln!("Error encrypting firmware for target {}, {}", target, e);
+            1
+        }
+    }
+}
+
+// This function pushes the firmware of a device's target to it and waits for the device to accept it.
+fn run_push_firmware(args: &[String]) -> i32 {
+    let (mac, ip, port) = match (args.first(), args.get(1).map(|ip| ip.parse()), args.get(2).map(|port| port.parse())) {
+        (Some(mac), Some(Ok(ip)), None) => (mac, ip, None),
+        (Some(mac), Some(Ok(ip)), Some(Ok(port))) => (mac, ip, Some(port)),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2
+        }
+    };
+    let push = match espota::prepare(mac, ip, port) {
+        Ok(push) => push,
+        Err(reason) => {
+            eprintln!("Cannot push firmware to {}, {}", mac, reason);
+            return 1
+        }
+    };
+    let (target, bytes) = (push.target.clone(), push.bytes);
+    match push.run() {
+        Ok(_) => {
+            println!("Pushed {} bytes of {} to {}.", bytes, target, mac);
+            0
+        }
+        Err(e) => {
+            eprintln!("Error pushing firmware to {}, {}", mac, e);
+            1
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+use crate::credentials::generate_secret;
+use crate::settings::SETTINGS;
+use crate::store;
+
+// The longest a command may wait for its device, a year.
+pub const MAX_EXPIRY_SECS: i64 = 365 * 24 * 60 * 60;
+// The longest finished commands may be kept, ten years.
+pub const MAX_HISTORY_DAYS: i64 = 3650;
+
+lazy_static! {
+    // Serializes read-modify-write cycles on the command queue. Holds the pending counts while they are current.
+    static ref COMMANDS_LOCK: Mutex<Option<PendingCounts>> = Mutex::new(None);
+}
+
+// The number of unfinished commands of each device, keyed by upper case MAC, so update checks do not load the queue.
+// They are current until the queue changes or the first of the commands expires.
+struct PendingCounts {
+    counts: HashMap<String, usize>,
+    valid_until: Option<DateTime<Utc>>,
+}
+
+impl PendingCounts {
+    // This function counts the unfinished commands in a loaded queue.
+    fn of(commands: &[Command]) -> PendingCounts {
+        let mut counts = HashMap::new();
+        for command in commands.iter().filter(|c| c.is_active()) {
+            *counts.entry(command.mac.to_uppercase()).or_insert(0) += 1;
+        }
+        let valid_until = commands.iter().filter(|c| c.is_active()).map(|c| c.expires).min();
+        PendingCounts { counts, valid_until }
+    }
+
+    // This function checks that no command counted has expired since.
+    fn is_current(&self, now: DateTime<Utc>) -> bool {
+        self.valid_until.map(|expires| now < expires).unwrap_or(true)
+    }
+}
+
+// What a device is told to do. Devices carry the command out themselves; `force_update` asks them to check for and
+// install an update now.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandKind {
+    Reboot,
+    FactoryReset,
+    ForceUpdate,
+}
+
+impl CommandKind {
+    pub fn parse(name: &str) -> Option<CommandKind> {
+        match name {
+            "reboot" => Some(CommandKind::Reboot),
+            "factory_reset" => Some(CommandKind::FactoryReset),
+            "force_update" => Some(CommandKind::ForceUpdate),
+            _ => None,
+        }
+    }
+}
+
+// Where a command is in its life. Pending and delivered commands are served to the device until it acknowledges them
+// or they expire.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandState {
+    Pending,
+    Delivered,
+    Done,
+    Failed,
+    Expired,
+    Cancelled,
+}
+
+// A command queued for a device, as stored in `commands.toml`. Finished commands are kept as history.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Command {
+    pub id: String,
+    pub mac: String,
+    pub command: CommandKind,
+    pub state: CommandState,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub delivered: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    // What the device reported when it acknowledged the command.
+    pub result: Option<String>,
+}
+
+impl Command {
+    pub fn is_active(&self) -> bool {
+        self.state == CommandState::Pending || self.state == CommandState::Delivered
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CommandStore {
+    commands: Vec<Command>,
+}
+
+// The command queue in the configuration directory.
+const COMMANDS: &str = "commands.toml";
+
+// This function loads the command queue, expiring commands that ran out of time and dropping history older than
+// `commands.history_days`.
+fn load_commands() -> Result<Vec<Command>, Box<dyn Error>> {
+    let mut commands = store::load::<CommandStore>(COMMANDS)?.commands;
+    let now = Utc::now();
+    for command in commands.iter_mut().filter(|c| c.is_active() && c.expires <= now) {
+        command.state = CommandState::Expired;
+        command.finished = Some(command.expires);
+    }
+    let horizon = now - Duration::days(SETTINGS.commands.history_days);
+    commands.retain(|c| c.finished.map(|finished| finished > horizon).unwrap_or(true));
+    Ok(commands)
+}
+
+// This function writes the command queue back, dropping the pending counts worked out from the old one.
+fn save_commands(pending: &mut Option<PendingCounts>, commands: Vec<Command>) -> Result<(), Box<dyn Error>> {
+    *pending = None;
+    store::save(COMMANDS, &CommandStore { commands })
+}
+
+// This function lists the commands of every device or only of `mac`, oldest first.
+pub fn list_commands(mac: Option<&str>) -> Result<Vec<Command>, Box<dyn Error>> {
+    let _guard = COMMANDS_LOCK.lock().unwrap();
+    Ok(load_commands()?.into_iter().filter(|c| mac.map(|mac| c.mac.eq_ignore_ascii_case(mac)).unwrap_or(true)).collect())
+}
+
+// This function counts the commands waiting for a device, which it is told about in its update checks.
+pub fn pending_count(mac: &str) -> usize {
+    let mut pending = COMMANDS_LOCK.lock().unwrap();
+    if !pending.as_ref().map(|p| p.is_current(Utc::now())).unwrap_or(false) {
+        match load_commands() {
+            Ok(commands) => *pending = Some(PendingCounts::of(&commands)),
+            Err(e) => {
+                tracing::error!(error = %e, "Error loading commands.toml.");
+                return 0
+            }
+        }
+    }
+    pending.as_ref().and_then(|p| p.counts.get(&mac.to_uppercase()).copied()).unwrap_or(0)
+}
+
+// This function parses the seconds until a command expires, which must lie between 1 and `MAX_EXPIRY_SECS`.
+pub fn parse_expiry(secs: &str) -> Result<i64, String> {
+    check_expiry(secs.trim().parse().unwrap_or(0))
+}
+
+fn check_expiry(secs: i64) -> Result<i64, String> {
+    if (1..=MAX_EXPIRY_SECS).contains(&secs) {
+        Ok(secs)
+    } else {
+        Err(format!("command expiries must lie between 1 and {} seconds", MAX_EXPIRY_SECS))
+    }
+}
+
+// This function queues a command for a device. It expires after `expiry_secs`, or `commands.expiry_secs` if not given.
+pub fn enqueue(mac: &str, command: CommandKind, expiry_secs: Option<i64>) -> Result<Command, Box<dyn Error>> {
+    let expiry_secs = check_expiry(expiry_secs.unwrap_or(SETTINGS.commands.expiry_secs))?;
+    let mut counts = COMMANDS_LOCK.lock().unwrap();
+    let mut commands = load_commands()?;
+    let now = Utc::now();
+    let queued = Command {
+        id: generate_secret(12),
+        mac: mac.to_uppercase(),
+        command,
+        state: CommandState::Pending,
+        created: now,
+        expires: now + Duration::seconds(expiry_secs),
+        delivered: None,
+        finished: None,
+        result: None,
+    };
+    commands.push(queued.clone());
+    save_commands(&mut counts, commands)?;
+    Ok(queued)
+}
+
+// This function cancels a command that was not finished yet. Returns `None` if there is no such command.
+pub fn cancel(id: &str) -> Result<Option<Command>, Box<dyn Error>> {
+    let mut counts = COMMANDS_LOCK.lock().unwrap();
+    let mut commands = load_commands()?;
+    let cancelled = commands.iter_mut().find(|c| c.id == id && c.is_active()).map(|command| {
+        command.state = CommandState::Cancelled;
+        command.finished = Some(Utc::now());
+        command.clone()
+    });
+    save_commands(&mut counts, commands)?;
+    Ok(cancelled)
+}
+
+// This function hands a device its unfinished commands, marking them delivered. Delivered commands are handed out
+// again until the device acknowledges them, in case it restarted before it could.
+pub fn take_pending(mac: &str) -> Result<Vec<Command>, Box<dyn Error>> {
+    let mut counts = COMMANDS_LOCK.lock().unwrap();
+    let mut commands = load_commands()?;
+    let now = Utc::now();
+    let mut pending = vec!();
+    for command in commands.iter_mut().filter(|c| c.mac.eq_ignore_ascii_case(mac) && c.is_active()) {
+        if command.state == CommandState::Pending {
+            command.state = CommandState::Delivered;
+            command.delivered = Some(now);
+        }
+        pending.push(command.clone());
+    }
+    if !pending.is_empty() {
+        save_commands(&mut counts, commands)?;
+    }
+    Ok(pending)
+}
+
+// This function records a device's acknowledgement of one of its commands. Returns `None` if the device has no
+// unfinished command with the id.
+pub fn acknowledge(mac: &str, id: &str, success: bool, result: Option<String>) -> Result<Option<Command>, Box<dyn Error>> {
+    let mut counts = COMMANDS_LOCK.lock().unwrap();
+    let mut commands = load_commands()?;
+    let acknowledged = commands.iter_mut().find(|c| c.id == id && c.mac.eq_ignore_ascii_case(mac) && c.is_active()).map(|command| {
+        command.state = if success { CommandState::Done } else { CommandState::Failed };
+        command.finished = Some(Utc::now());
+        command.result = result;
+        command.clone()
+    });
+    save_commands(&mut counts, commands)?;
+    Ok(acknowledged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, CommandKind, CommandState, PendingCounts};
+    use chrono::{Duration, TimeZone, Utc};
+
+    fn command(mac: &str, state: CommandState, expires_in: i64) -> Command {
+        let created = Utc.ymd(2026, 10, 18).and_hms(12, 0, 0);
+        Command {
+            id: String::from("id"),
+            mac: mac.to_string(),
+            command: CommandKind::Reboot,
+            state,
+            created,
+            expires: created + Duration::minutes(expires_in),
+            delivered: None,
+            finished: None,
+            result: None,
+        }
+    }
+
+    #[test]
+    fn pending_counts_last_until_the_first_command_expires() {
+        let commands = vec!(
+            command("aa:bb:cc:dd:ee:ff", CommandState::Pending, 10),
+            command("AA:BB:CC:DD:EE:FF", CommandState::Delivered, 5),
+            command("AA:BB:CC:DD:EE:FF", CommandState::Done, 1),
+            command("11:22:33:44:55:66", CommandState::Pending, 20),
+        );
+        let pending = PendingCounts::of(&commands);
+        assert_eq!(pending.counts.get("AA:BB:CC:DD:EE:FF"), Some(&2));
+        assert_eq!(pending.counts.get("11:22:33:44:55:66"), Some(&1));
+        let created = commands[0].created;
+        assert!(pending.is_current(created + Duration::minutes(4)));
+        assert!(!pending.is_current(created + Duration::minutes(5)));
+        assert!(PendingCounts::of(&[]).is_current(created));
+    }
+}
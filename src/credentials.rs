@@ -0,0 +1,208 @@
+use actix_web::http::HeaderMap;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+
+use crate::settings::SETTINGS;
+use crate::{get_config_path, store};
+
+lazy_static! {
+    // Serializes read-modify-write cycles on the device secrets.
+    static ref DEVICE_SECRETS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// A per-device secret as stored in the `device_secrets` file. Only the salted hash of the secret is kept.
+pub struct DeviceSecret {
+    pub mac: String,
+    pub salt: String,
+    pub hash: String,
+}
+
+// This function generates a random alphanumeric secret of the given length.
+pub fn generate_secret(len: usize) -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(len).collect()
+}
+
+// This function hashes a secret with its salt as hex encoded SHA-256.
+pub fn hash_secret(salt: &str, secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(secret.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+// This function compares a presented secret against a stored salted hash in constant time.
+pub fn secret_matches(salt: &str, hash: &str, presented: &str) -> bool {
+    hash_secret(salt, presented).as_bytes().ct_eq(hash.as_bytes()).into()
+}
+
+// This function returns the path of the file holding the hashed per-device secrets.
+fn device_secrets_path() -> String {
+    format!("{}{}", get_config_path(), "device_secrets")
+}
+
+// This function loads the `device_secrets` file. Each line holds `mac salt hash`. A missing file means no device has a secret.
+pub fn load_device_secrets() -> std::io::Result<Vec<DeviceSecret>> {
+    match std::fs::read_to_string(device_secrets_path()) {
+        Ok(file) => Ok(parse_device_secrets(&file)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec!()),
+        Err(e) => Err(e),
+    }
+}
+
+// This function parses the lines of a `device_secrets` file, skipping malformed ones.
+fn parse_device_secrets(file: &str) -> Vec<DeviceSecret> {
+    let mut secrets: Vec<DeviceSecret> = vec!();
+    for line in file.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() == 3 {
+            secrets.push(DeviceSecret {
+                mac: fields[0].to_string(),
+                salt: fields[1].to_string(),
+                hash: fields[2].to_string(),
+            });
+        }
+    }
+    secrets
+}
+
+// This function writes the per-device secrets back into the `device_secrets` file, replacing the old copy.
+fn save_device_secrets(secrets: &[DeviceSecret]) -> std::io::Result<()> {
+    store::write_atomically(&device_secrets_path(), &format_device_secrets(secrets))
+}
+
+// This function formats secrets as the lines of a `device_secrets` file.
+fn format_device_secrets(secrets: &[DeviceSecret]) -> String {
+    secrets.iter().map(|secret| format!("{} {} {}\n", secret.mac, secret.salt, secret.hash)).collect()
+}
+
+// This function issues a new secret for the device, replacing any previous one. The plaintext is returned once and never stored.
+pub fn issue_device_secret(mac: &str) -> std::io::Result<String> {
+    let _guard = DEVICE_SECRETS_LOCK.lock().unwrap();
+    let mut secrets = load_device_secrets()?;
+    let secret = replace_secret(&mut secrets, mac);
+    save_device_secrets(&secrets)?;
+    Ok(secret)
+}
+
+// This function generates a secret for `mac` in place of any it had and returns the plaintext.
+fn replace_secret(secrets: &mut Vec<DeviceSecret>, mac: &str) -> String {
+    let secret = generate_secret(32);
+    let salt = generate_secret(16);
+    secrets.retain(|s| !s.mac.eq_ignore_ascii_case(mac));
+    secrets.push(DeviceSecret {
+        mac: mac.to_string(),
+        hash: hash_secret(&salt, &secret),
+        salt,
+    });
+    secret
+}
+
+// This function verifies a presented secret against the one issued to `mac`. Returns `None` if the device has no secret of its own.
+pub fn verify_device_secret(mac: &str, presented: &str) -> std::io::Result<Option<bool>> {
+    Ok(find_secret(&load_device_secrets()?, mac, presented))
+}
+
+// This function checks a presented secret against the one of `mac` in `secrets`, `None` if it has none.
+fn find_secret(secrets: &[DeviceSecret], mac: &str, presented: &str) -> Option<bool> {
+    secrets.iter()
+        .find(|s| s.mac.eq_ignore_ascii_case(mac))
+        .map(|s| secret_matches(&s.salt, &s.hash, presented))
+}
+
+// This function extracts the device API key from the request. The key is taken from, in order, an `Authorization: Bearer`
+// header, the password of HTTP basic auth as sent by `ESP8266httpUpdate::setAuthorization` or an `x-api-key` header.
+// The legacy `version?key` form in the version header is only honoured when `legacy_version_key` is enabled.
+pub fn extract_api_key(headers: &HeaderMap) -> Option<String> {
+    extract_api_key_with(headers, SETTINGS.legacy_version_key)
+}
+
+// This function extracts the device API key from `headers`, accepting the legacy `version?key` form if `legacy` is set.
+fn extract_api_key_with(headers: &HeaderMap, legacy: bool) -> Option<String> {
+    if let Some(value) = headers.get("authorization").and_then(|h| h.to_str().ok()) {
+        let mut parts = value.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => return Some(token.trim().to_string()),
+            (Some(scheme), Some(encoded)) if scheme.eq_ignore_ascii_case("basic") => {
+                // Malformed credentials fall through to the other sources.
+                let key = base64::decode(encoded.trim()).ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok())
+                    .and_then(|credentials| credentials.split_once(':').map(|(_, key)| key.to_string()));
+                if key.is_some() {
+                    return key
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(key) = headers.get("x-api-key").and_then(|h| h.to_str().ok()) {
+        return Some(key.to_string())
+    }
+    if legacy {
+        let version = headers.get("x-esp8266-version").or_else(|| headers.get("x-esp32-version"))?;
+        return version.to_str().ok()?.split_once('?').map(|(_, key)| key.to_string())
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_api_key_with, find_secret, format_device_secrets, parse_device_secrets, replace_secret};
+    use crate::settings::Settings;
+    use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn issued_secrets_bind_the_device() {
+        let mut secrets = vec!();
+        let first = replace_secret(&mut secrets, "aa:bb:cc:dd:ee:ff");
+        let secret = replace_secret(&mut secrets, "AA:BB:CC:DD:EE:FF");
+        let secrets = parse_device_secrets(&format_device_secrets(&secrets));
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(find_secret(&secrets, "aa:bb:cc:dd:ee:ff", &secret), Some(true));
+        // A bound device is refused with its replaced secret or a shared key, rather than falling back to the key store.
+        assert_eq!(find_secret(&secrets, "AA:BB:CC:DD:EE:FF", &first), Some(false));
+        assert_eq!(find_secret(&secrets, "AA:BB:CC:DD:EE:FF", "shared-api-key"), Some(false));
+        assert_eq!(find_secret(&secrets, "11:22:33:44:55:66", "shared-api-key"), None);
+    }
+
+    #[test]
+    fn api_keys_are_taken_in_order_of_precedence() {
+        let basic = format!("Basic {}", base64::encode("user:basic-key"));
+        let mut sources = vec!(("x-esp8266-version", "1.0?legacy-key"));
+        assert_eq!(extract_api_key_with(&headers(&sources), true).as_deref(), Some("legacy-key"));
+        sources.push(("x-api-key", "header-key"));
+        assert_eq!(extract_api_key_with(&headers(&sources), true).as_deref(), Some("header-key"));
+        sources.push(("authorization", &basic));
+        assert_eq!(extract_api_key_with(&headers(&sources), true).as_deref(), Some("basic-key"));
+        sources.pop();
+        sources.push(("authorization", "Bearer bearer-key"));
+        assert_eq!(extract_api_key_with(&headers(&sources), true).as_deref(), Some("bearer-key"));
+    }
+
+    #[test]
+    fn malformed_basic_auth_falls_through() {
+        for value in &["Basic not-base64!".to_string(), format!("Basic {}", base64::encode("no-colon"))] {
+            let request = headers(&[("authorization", value), ("x-api-key", "header-key")]);
+            assert_eq!(extract_api_key_with(&request, false).as_deref(), Some("header-key"));
+            assert_eq!(extract_api_key_with(&headers(&[("authorization", value)]), false), None);
+        }
+    }
+
+    #[test]
+    fn legacy_version_keys_are_off_by_default() {
+        let legacy = Settings::default().legacy_version_key;
+        assert!(!legacy);
+        assert_eq!(extract_api_key_with(&headers(&[("x-esp8266-version", "1.0?legacy-key")]), legacy), None);
+        assert_eq!(extract_api_key_with(&headers(&[("x-esp8266-version", "1.0")]), true), None);
+    }
+}
@@ -0,0 +1,431 @@
+use actix_web::body::{Body, SizedStream};
+use actix_web::http::HeaderMap;
+use actix_service::Service;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, TimeZone, Utc};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::events::DownloadBody;
+use crate::fleet::{self, FleetDevice, UpdateStatus};
+use crate::history::{self, UpdateMethod, UpdateRecord, UpdateResult};
+use crate::metrics::RouteLabel;
+use crate::proxy::ClientInfo;
+use crate::settings::SETTINGS;
+use crate::{admit, credentials, encryption, firmware, keys, metrics, rollout, version_label};
+
+// The longest sleep the DDI polling configuration can express.
+const MAX_SLEEP_SECS: u64 = 24 * 60 * 60 - 1;
+// What firmware is as a software module of a DDI deployment.
+const PART: &str = "os";
+
+lazy_static! {
+    // The encrypted image each device was last offered, keyed by MAC.
+    static ref ENCRYPTED_IMAGES: Mutex<HashMap<String, EncryptedImage>> = Mutex::new(HashMap::new());
+}
+
+// An image encrypted for one device, with the firmware it holds.
+struct EncryptedImage {
+    target: String,
+    version: DateTime<Utc>,
+    image: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct ControllerPath {
+    tenant: String,
+    controller_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ActionPath {
+    tenant: String,
+    controller_id: String,
+    action_id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ArtifactPath {
+    tenant: String,
+    controller_id: String,
+    module_id: i64,
+    file_name: String,
+}
+
+#[derive(Serialize)]
+struct Link {
+    href: String,
+}
+
+#[derive(Serialize)]
+struct Polling {
+    sleep: String,
+}
+
+#[derive(Serialize)]
+struct ControllerConfig {
+    polling: Polling,
+}
+
+// What a controller is told when it polls: how long to sleep, and the deployment to fetch if there is one.
+#[derive(Serialize)]
+struct ControllerBase {
+    config: ControllerConfig,
+    #[serde(rename = "_links")]
+    links: BTreeMap<&'static str, Link>,
+}
+
+#[derive(Serialize)]
+struct Hashes {
+    sha1: String,
+    md5: String,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct Artifact {
+    filename: String,
+    hashes: Hashes,
+    size: usize,
+    #[serde(rename = "_links")]
+    links: BTreeMap<&'static str, Link>,
+}
+
+#[derive(Serialize)]
+struct Chunk {
+    part: &'static str,
+    version: String,
+    name: String,
+    artifacts: Vec<Artifact>,
+}
+
+#[derive(Serialize)]
+struct Deployment {
+    download: &'static str,
+    update: &'static str,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Serialize)]
+struct DeploymentBase {
+    id: String,
+    deployment: Deployment,
+}
+
+#[derive(Deserialize)]
+pub struct Feedback {
+    status: FeedbackStatus,
+}
+
+#[derive(Deserialize)]
+struct FeedbackStatus {
+    execution: String,
+    result: FeedbackResult,
+    #[serde(default)]
+    details: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct FeedbackResult {
+    finished: String,
+}
+
+// What a device is offered when it polls.
+enum Offer {
+    Deployment(DateTime<Utc>),
+    HeldByRollout,
+    UpToDate,
+}
+
+// This function returns the id of the action deploying a firmware version, which is also the id of its software
+// module. Both are the compile time, so feedback can be matched to the version after a newer one was uploaded.
+fn action_id(version: DateTime<Utc>) -> i64 {
+    version.timestamp()
+}
+
+// This function checks the `TargetToken` or `GatewayToken` a controller authenticates with. A target token is the
+// device's own secret if it was issued one, or a device API key; a gateway token is a device API key, and is refused for
+// devices that were issued a secret, as those may only authenticate with it.
+fn check_token(headers: &HeaderMap, mac: &str) -> Result<bool, Box<dyn Error>> {
+    let (scheme, token) = match headers.get("authorization").and_then(|h| h.to_str().ok()).and_then(|v| v.split_once(' ')) {
+        Some((scheme, token)) => (scheme, token.trim()),
+        _ => return Ok(false)
+    };
+    if scheme.eq_ignore_ascii_case("TargetToken") {
+        match credentials::verify_device_secret(mac, token)? {
+            Some(valid) => Ok(valid),
+            _ => keys::validate_key(token),
+        }
+    } else if scheme.eq_ignore_ascii_case("GatewayToken") {
+        match credentials::verify_device_secret(mac, token)? {
+            Some(_) => Ok(false),
+            _ => keys::validate_key(token),
+        }
+    } else {
+        Ok(false)
+    }
+}
+
+// This function admits a controller like any other device request and returns the registered device it is. The
+// controller id is the MAC.
+fn authenticate(req: &HttpRequest, tenant: &str, controller_id: &str) -> Result<FleetDevice, HttpResponse> {
+    if !tenant.eq_ignore_ascii_case(&SETTINGS.hawkbit.tenant) {
+        return Err(HttpResponse::NotFound().finish())
+    }
+    let mac = controller_id.to_uppercase();
+    // DDI clients run on the ESP32, so they are held to its client certificate requirement.
+    admit(req, &mac, true, || check_token(req.headers(), &mac))?;
+    match fleet::list_fleet() {
+        Ok(devices) => fleet::find(devices, &mac).ok_or_else(|| {
+            warn!(mac = %mac, "DDI controller is not a registered device.");
+            HttpResponse::NotFound().header("x-rota-reason", "unknown_device").body("unknown_device")
+        }),
+        Err(e) => {
+            error!(error = %e, "Error loading fleet for DDI.");
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// This function checks whether the controller closed the action deploying a version already, successfully or not. A
+// failed version is not offered again, a newer one is.
+fn closed(mac: &str, version: DateTime<Utc>) -> bool {
+    match fleet::last_closed_action(mac) {
+        Ok(closed) => closed == Some(version),
+        Err(e) => {
+            error!(error = %e, "Error loading device_status.toml.");
+            false
+        }
+    }
+}
+
+// This function decides what a device is offered: the latest firmware of its target if it runs an older or unknown
+// version, its rollout includes it and it has not closed the action for it.
+fn offer(device: &FleetDevice) -> Offer {
+    let latest = match device.latest_version {
+        Some(latest) if device.reported_version.map(|running| running < latest).unwrap_or(true) => latest,
+        _ => return Offer::UpToDate
+    };
+    if closed(&device.mac, latest) {
+        Offer::UpToDate
+    } else if !rollout::includes(&device.target, &device.mac) {
+        Offer::HeldByRollout
+    } else {
+        Offer::Deployment(latest)
+    }
+}
+
+// This function returns the URL of a resource of a controller, on the host and scheme the controller called.
+fn href(req: &HttpRequest, tenant: &str, controller_id: &str, resource: &str) -> Link {
+    let scheme = if ClientInfo::of(req).https { "https" } else { "http" };
+    Link { href: format!("{}://{}/{}/controller/v1/{}{}", scheme, req.connection_info().host(), tenant, controller_id, resource) }
+}
+
+// This function returns the file name the firmware of a target is downloaded as.
+fn artifact_name(target: &str) -> String {
+    format!("{}.bin", target)
+}
+
+// This function loads the binary of a deployment: the firmware of the device's target, encrypted as an
+// `esp_encrypted_img` image if the device or its group has a delivery key. Every encryption differs, so the image a
+// device is encrypted is kept until the next version, for the download to match the hashes of its deployment. Returns
+// whether it is encrypted, or the response to send instead.
+fn device_image(device: &FleetDevice, version: DateTime<Utc>) -> Result<(Vec<u8>, bool), HttpResponse> {
+    let mut images = ENCRYPTED_IMAGES.lock().unwrap();
+    if let Some(cached) = images.get(&device.mac).filter(|cached| cached.target == device.target && cached.version == version) {
+        return Ok((cached.image.clone(), true))
+    }
+    let image = match firmware::load_image(&device.target) {
+        Ok(image) => image,
+        Err(reason) => {
+            error!(decision = "refuse", reason, target = %device.target, "Refusing to send firmware.");
+            return Err(HttpResponse::ServiceUnavailable().header("x-rota-reason", reason).body(reason))
+        }
+    };
+    match encryption::encrypt_for_device(&device.mac, Some(&device.group), &image) {
+        Ok(Some(encrypted)) => {
+            images.insert(device.mac.clone(), EncryptedImage { target: device.target.clone(), version, image: encrypted.clone() });
+            Ok((encrypted, true))
+        }
+        Ok(None) => Ok((image, false)),
+        Err(e) => {
+            error!(mac = %device.mac, error = %e, "Error encrypting firmware for device.");
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// This function answers a controller polling for work, linking the deployment it should fetch if there is one.
+async fn controller_base(req: HttpRequest, path: web::Path<ControllerPath>) -> HttpResponse {
+    let device = match authenticate(&req, &path.tenant, &path.controller_id) {
+        Ok(device) => device,
+        Err(response) => return response
+    };
+    let mut links = BTreeMap::new();
+    let status = match offer(&device) {
+        Offer::Deployment(version) => {
+            let resource = format!("/deploymentBase/{}", action_id(version));
+            links.insert("deploymentBase", href(&req, &path.tenant, &path.controller_id, &resource));
+            UpdateStatus::UpdateAvailable
+        }
+        Offer::HeldByRollout => UpdateStatus::HeldByRollout,
+        Offer::UpToDate => UpdateStatus::UpToDate,
+    };
+    info!(decision = ?status, mac = %device.mac, target = %device.target, "DDI controller polled.");
+    metrics::record_check("ddi", status == UpdateStatus::UpdateAvailable);
+    fleet::record_poll(&device.mac, &ClientInfo::of(&req).ip_string(), status);
+    let sleep = SETTINGS.hawkbit.polling_interval_secs.min(MAX_SLEEP_SECS);
+    HttpResponse::Ok().json(ControllerBase {
+        config: ControllerConfig { polling: Polling { sleep: format!("{:02}:{:02}:{:02}", sleep / 3600, sleep / 60 % 60, sleep % 60) } },
+        links,
+    })
+}
+
+// This function describes the deployment of the latest firmware of the device's target, with the hashes and links of
+// its binary.
+async fn deployment_base(req: HttpRequest, path: web::Path<ActionPath>) -> HttpResponse {
+    let device = match authenticate(&req, &path.tenant, &path.controller_id) {
+        Ok(device) => device,
+        Err(response) => return response
+    };
+    let version = match offer(&device) {
+        Offer::Deployment(version) if action_id(version) == path.action_id => version,
+        _ => return HttpResponse::NotFound().body("No such action.")
+    };
+    let (image, _) = match device_image(&device, version) {
+        Ok(image) => image,
+        Err(response) => return response
+    };
+    let filename = artifact_name(&device.target);
+    let download = format!("/softwaremodules/{}/artifacts/{}", action_id(version), filename);
+    let mut links = BTreeMap::new();
+    for (name, resource) in [("download", download.clone()), ("download-http", download.clone()),
+                             ("md5sum", format!("{}.MD5SUM", download)), ("md5sum-http", format!("{}.MD5SUM", download))] {
+        links.insert(name, href(&req, &path.tenant, &path.controller_id, &resource));
+    }
+    HttpResponse::Ok().json(DeploymentBase {
+        id: path.action_id.to_string(),
+        deployment: Deployment {
+            download: "forced",
+            update: "forced",
+            chunks: vec!(Chunk {
+                part: PART,
+                version: version_label(&version),
+                name: device.target.clone(),
+                artifacts: vec!(Artifact {
+                    filename,
+                    hashes: Hashes {
+                        sha1: hex::encode(Sha1::digest(&image)),
+                        md5: hex::encode(Md5::digest(&image)),
+                        sha256: hex::encode(Sha256::digest(&image)),
+                    },
+                    size: image.len(),
+                    links,
+                }),
+            }),
+        },
+    })
+}
+
+// This function serves the binary of a deployment, or its MD5 checksum file.
+async fn download_artifact(req: HttpRequest, path: web::Path<ArtifactPath>) -> HttpResponse {
+    let device = match authenticate(&req, &path.tenant, &path.controller_id) {
+        Ok(device) => device,
+        Err(response) => return response
+    };
+    let version = match offer(&device) {
+        Offer::Deployment(version) if action_id(version) == path.module_id => version,
+        _ => return HttpResponse::NotFound().body("No such software module.")
+    };
+    let filename = artifact_name(&device.target);
+    let checksum = path.file_name.strip_suffix(".MD5SUM") == Some(filename.as_str());
+    if !checksum && path.file_name != filename {
+        return HttpResponse::NotFound().body("No such artifact.")
+    }
+    let (image, encrypted) = match device_image(&device, version) {
+        Ok(image) => image,
+        Err(response) => return response
+    };
+    if checksum {
+        return HttpResponse::Ok().content_type("text/plain").body(format!("{}  {}\n", hex::encode(Md5::digest(&image)), filename))
+    }
+    let client_ip = ClientInfo::of(&req).ip_string();
+    info!(decision = "send_firmware", mac = %device.mac, target = %device.target, latest = %version, bytes = image.len(),
+          "Sending firmware to DDI controller.");
+    metrics::record_download(&device.target, &version_label(&version), image.len());
+    fleet::record_poll(&device.mac, &client_ip, UpdateStatus::FirmwareSent);
+    let body = DownloadBody::start(image, &device.mac, &client_ip, &device.target, version);
+    let mut response = HttpResponse::Ok();
+    if encrypted {
+        response.header("x-rota-encryption", "esp_encrypted_img");
+    }
+    response
+        .content_type("application/octet-stream")
+        .header("content-disposition", format!("attachment;filename={}", filename))
+        .body(Body::from_message(SizedStream::new(body.len() as u64, body)))
+}
+
+// This function records the feedback of a controller on a deployment in the update history. A successful deployment
+// also records the version as running on the device.
+async fn feedback(req: HttpRequest, path: web::Path<ActionPath>, feedback: web::Json<Feedback>) -> HttpResponse {
+    let device = match authenticate(&req, &path.tenant, &path.controller_id) {
+        Ok(device) => device,
+        Err(response) => return response
+    };
+    let version = match Utc.timestamp_opt(path.action_id, 0).single() {
+        Some(version) => version,
+        _ => return HttpResponse::NotFound().body("No such action.")
+    };
+    let status = &feedback.status;
+    let result = match (status.execution.as_str(), status.result.finished.as_str()) {
+        ("closed", "failure") | ("rejected", _) => UpdateResult::Failed,
+        ("closed", _) => UpdateResult::Completed,
+        ("canceled", _) => UpdateResult::Aborted,
+        _ => UpdateResult::InProgress,
+    };
+    info!(mac = %device.mac, action = path.action_id, execution = %status.execution, finished = %status.result.finished,
+          details = ?status.details, "DDI controller sent feedback.");
+    if result == UpdateResult::Completed {
+        if let Err(e) = fleet::record_report(&device.mac, version) {
+            error!(mac = %device.mac, error = %e, "Error saving device_status.toml.");
+        }
+    }
+    if result != UpdateResult::InProgress {
+        if let Err(e) = fleet::record_closed_action(&device.mac, version) {
+            error!(mac = %device.mac, error = %e, "Error saving device_status.toml.");
+        }
+    }
+    history::record(&UpdateRecord {
+        time: Utc::now(),
+        mac: device.mac,
+        target: device.target,
+        version,
+        method: UpdateMethod::Ddi,
+        result,
+        sent: 0,
+        bytes: 0,
+        error: if result == UpdateResult::Failed { Some(status.details.join("; ")) } else { None },
+    });
+    HttpResponse::Ok().finish()
+}
+
+// This function registers the DDI routes, `/<tenant>/controller/v1/<controller id>/...`, if the API is enabled.
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    if !SETTINGS.hawkbit.enabled {
+        return
+    }
+    cfg.service(web::scope("/{tenant}/controller/v1/{controller_id}")
+        .wrap_fn(|req, srv| {
+            req.extensions_mut().insert(RouteLabel("ddi"));
+            srv.call(req)
+        })
+        .route("", web::get().to(controller_base))
+        .route("/deploymentBase/{action_id}", web::get().to(deployment_base))
+        .route("/deploymentBase/{action_id}/feedback", web::post().to(feedback))
+        .route("/softwaremodules/{module_id}/artifacts/{file_name}", web::get().to(download_artifact)));
+}
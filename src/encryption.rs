@@ -0,0 +1,167 @@
+use aes_gcm::aead::{Aead, AeadInPlace, NewAead};
+use aes_gcm::aes::cipher::consts::U16;
+use aes_gcm::aes::Aes256;
+use aes_gcm::{Aes256Gcm, AesGcm};
+use rand::RngCore;
+use rsa::{PaddingScheme, PublicKey, PublicKeyParts, RSAPublicKey};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::io;
+
+use crate::settings::SETTINGS;
+use crate::store;
+
+// Files encrypted at rest start with this marker, followed by the 12 byte nonce and the AES-256-GCM ciphertext and tag.
+const AT_REST_MAGIC: &[u8] = b"ROTAENC1";
+const AT_REST_NONCE_LEN: usize = 12;
+
+// Header layout of the ESP-IDF `esp_encrypted_img` component (pre-encrypted OTA images).
+const ENCRYPTED_IMG_MAGIC: u32 = 0x0788_b6cf;
+const ENCRYPTED_IMG_KEY_BITS: usize = 3072;
+const ENCRYPTED_IMG_RESERVED_LEN: usize = 88;
+
+lazy_static! {
+    // The server key firmware is stored with, loaded once from `encryption.key_path`.
+    static ref AT_REST_KEY: Option<Aes256Gcm> = load_at_rest_key();
+    // The device and group public keys, keyed by lower case MAC or group name.
+    static ref DEVICE_KEYS: HashMap<String, RSAPublicKey> = load_public_keys(&SETTINGS.encryption.devices);
+    static ref GROUP_KEYS: HashMap<String, RSAPublicKey> = load_public_keys(&SETTINGS.encryption.groups);
+}
+
+// This function loads the base64 encoded 32 byte key from `encryption.key_path`, if one is configured.
+fn load_at_rest_key() -> Option<Aes256Gcm> {
+    let path = &SETTINGS.encryption.key_path;
+    if path.is_empty() {
+        return None
+    }
+    match std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|file| base64::decode(file.trim()).map_err(|e| e.to_string())) {
+        Ok(key) => match <[u8; 32]>::try_from(key.as_slice()) {
+            Ok(key) => Some(Aes256Gcm::new(&key.into())),
+            _ => panic!("Error loading firmware encryption key {}, it must be 32 bytes.", path),
+        },
+        Err(e) => panic!("Error loading firmware encryption key {}, {}", path, e)
+    }
+}
+
+// This function loads the PEM encoded RSA public keys configured for devices or groups.
+fn load_public_keys(paths: &HashMap<String, String>) -> HashMap<String, RSAPublicKey> {
+    let mut keys = HashMap::new();
+    for (name, path) in paths.iter() {
+        let loaded = std::fs::read(path).map_err(|e| e.to_string())
+            .and_then(|pem| rsa::pem::parse(pem).map_err(|e| e.to_string()))
+            .and_then(|pem| RSAPublicKey::try_from(pem).map_err(|e| e.to_string()));
+        match loaded {
+            Ok(key) if key.size() * 8 == ENCRYPTED_IMG_KEY_BITS => {
+                keys.insert(name.to_lowercase(), key);
+            }
+            Ok(_) => panic!("Error loading encryption key {} for {}, esp_encrypted_img needs an RSA-3072 key.", path, name),
+            Err(e) => panic!("Error loading encryption key {} for {}, {}", path, name, e)
+        }
+    }
+    keys
+}
+
+// This function loads every configured key, so a broken key stops the server before it accepts requests.
+pub fn load_keys() {
+    if AT_REST_KEY.is_some() || !DEVICE_KEYS.is_empty() || !GROUP_KEYS.is_empty() {
+        tracing::info!(at_rest = AT_REST_KEY.is_some(), device_keys = DEVICE_KEYS.len(), group_keys = GROUP_KEYS.len(),
+                       "Loaded firmware encryption keys.");
+    }
+}
+
+// This function generates a new at rest key and writes it to `path`.
+pub fn generate_key(path: &str) -> Result<(), Box<dyn Error>> {
+    let mut key = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut key);
+    match store::create_private(path, &base64::encode(key)) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Err(format!("{} already exists", path).into()),
+        result => Ok(result?),
+    }
+}
+
+// This function checks whether a stored file is encrypted at rest.
+fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(AT_REST_MAGIC)
+}
+
+// This function reads a firmware binary, decrypting it if it is stored encrypted.
+pub fn read_firmware(path: &str) -> io::Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    if !is_encrypted(&data) {
+        return Ok(data)
+    }
+    let key = AT_REST_KEY.as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "firmware is encrypted but no encryption key is configured"))?;
+    if data.len() < AT_REST_MAGIC.len() + AT_REST_NONCE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "encrypted firmware is truncated"))
+    }
+    let (nonce, ciphertext) = data[AT_REST_MAGIC.len()..].split_at(AT_REST_NONCE_LEN);
+    let nonce: [u8; AT_REST_NONCE_LEN] = nonce.try_into().unwrap();
+    key.decrypt(&nonce.into(), ciphertext)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "firmware does not decrypt with the configured key"))
+}
+
+// This function writes a firmware binary, encrypted if an at rest key is configured.
+pub fn write_firmware(path: &str, binary: &[u8]) -> io::Result<()> {
+    let key = match AT_REST_KEY.as_ref() {
+        Some(key) => key,
+        _ => return std::fs::write(path, binary)
+    };
+    let mut nonce = [0u8; AT_REST_NONCE_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    let ciphertext = key.encrypt(&nonce.into(), binary)
+        .map_err(|_| io::Error::other("error encrypting firmware"))?;
+    let mut data = Vec::with_capacity(AT_REST_MAGIC.len() + nonce.len() + ciphertext.len());
+    data.extend_from_slice(AT_REST_MAGIC);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    std::fs::write(path, data)
+}
+
+// This function encrypts a plaintext firmware file in place. Returns false if it was already encrypted.
+pub fn encrypt_in_place(path: &str) -> io::Result<bool> {
+    if AT_REST_KEY.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no encryption key is configured"))
+    }
+    let data = std::fs::read(path)?;
+    if is_encrypted(&data) {
+        return Ok(false)
+    }
+    write_firmware(path, &data)?;
+    Ok(true)
+}
+
+// This function returns the delivery key of a device: its own key, else its group's key.
+fn delivery_key(mac: &str, group: Option<&str>) -> Option<&'static RSAPublicKey> {
+    DEVICE_KEYS.get(&mac.to_lowercase()).or_else(|| group.and_then(|group| GROUP_KEYS.get(&group.to_lowercase())))
+}
+
+// This function encrypts a binary for a device that has a delivery key, in the `esp_encrypted_img` format: a 512 byte
+// header holding the RSA-OAEP wrapped AES-256-GCM key, the IV, the plaintext size and the GCM tag, followed by the
+// ciphertext. Returns `None` if the device receives plaintext.
+pub fn encrypt_for_device(mac: &str, group: Option<&str>, binary: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let public_key = match delivery_key(mac, group) {
+        Some(key) => key,
+        _ => return Ok(None)
+    };
+    let mut rng = rand::rngs::OsRng;
+    let mut gcm_key = [0u8; 32];
+    let mut iv = [0u8; 16];
+    rng.fill_bytes(&mut gcm_key);
+    rng.fill_bytes(&mut iv);
+    let wrapped_key = public_key.encrypt(&mut rng, PaddingScheme::new_oaep::<sha2::Sha256>(), &gcm_key)?;
+    let cipher = AesGcm::<Aes256, U16>::new(&gcm_key.into());
+    let mut ciphertext = binary.to_vec();
+    let tag = cipher.encrypt_in_place_detached(&iv.into(), b"", &mut ciphertext)
+        .map_err(|_| "error encrypting firmware")?;
+    let mut image = Vec::with_capacity(512 + ciphertext.len());
+    image.extend_from_slice(&ENCRYPTED_IMG_MAGIC.to_le_bytes());
+    image.extend_from_slice(&wrapped_key);
+    image.extend_from_slice(&iv);
+    image.extend_from_slice(&(binary.len() as u32).to_le_bytes());
+    image.extend_from_slice(&tag);
+    image.extend_from_slice(&[0u8; ENCRYPTED_IMG_RESERVED_LEN]);
+    image.extend_from_slice(&ciphertext);
+    Ok(Some(image))
+}
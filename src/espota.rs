@@ -0,0 +1,327 @@
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::events::{self, Event};
+use crate::history::{self, UpdateMethod, UpdateRecord, UpdateResult};
+use crate::settings::SETTINGS;
+use crate::{firmware, find_device, latest_firmware_date, load_targets, unassigned};
+
+// Invitation command to flash the application, and the command answering an authentication challenge.
+const FLASH: u32 = 0;
+const AUTH: u32 = 200;
+// Size of the chunks the firmware is sent in, one TCP segment like espota.py sends.
+const CHUNK: usize = 1460;
+// How long a device may take to check and activate the firmware after the last chunk.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(60);
+// Steps, in percent of the firmware, progress is published and recorded in.
+const PROGRESS_STEP: usize = 10;
+
+lazy_static! {
+    // Devices firmware is being pushed to, so two pushes to a device cannot overlap.
+    static ref ACTIVE: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+// A push of the current firmware of a device's target, made by `prepare` and sent by `run`.
+#[derive(Serialize)]
+pub struct Push {
+    pub mac: String,
+    pub device: SocketAddr,
+    pub target: String,
+    pub version: DateTime<Utc>,
+    pub bytes: usize,
+    #[serde(skip)]
+    image: Vec<u8>,
+}
+
+// This function returns the hex MD5 digest of some data, which is what ArduinoOTA checks images and passwords with.
+fn md5_hex(data: &[u8]) -> String {
+    hex::encode(Md5::digest(data))
+}
+
+// This function answers an authentication challenge the way ArduinoOTA checks it, `md5(md5(password):nonce:cnonce)`.
+fn auth_response(password: &str, nonce: &str, cnonce: &str) -> String {
+    md5_hex(format!("{}:{}:{}", md5_hex(password.as_bytes()), nonce, cnonce).as_bytes())
+}
+
+// This function waits for the device to answer a UDP message.
+fn answer(socket: &UdpSocket) -> Result<String, Box<dyn Error>> {
+    let mut buffer = [0u8; 64];
+    match socket.recv(&mut buffer) {
+        Ok(len) => Ok(String::from_utf8_lossy(&buffer[..len]).trim().to_string()),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            Err("the device did not answer".into()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+// This function invites a device to fetch an image from `host_port`, and authenticates if the device asks to.
+fn invite(device: SocketAddr, host_port: u16, image: &[u8], password: &str, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let local: IpAddr = if device.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+    let socket = UdpSocket::bind((local, 0))?;
+    socket.connect(device)?;
+    socket.set_read_timeout(Some(timeout))?;
+    let image_md5 = md5_hex(image);
+    socket.send(format!("{} {} {} {}\n", FLASH, host_port, image.len(), image_md5).as_bytes())?;
+    let reply = answer(&socket)?;
+    if reply == "OK" {
+        return Ok(())
+    }
+    let nonce = match reply.strip_prefix("AUTH ") {
+        Some(nonce) => nonce.trim(),
+        _ => return Err(format!("the device refused the invitation: {}", reply).into())
+    };
+    if password.is_empty() {
+        return Err("the device requires a password, set espota.password in rota.toml".into())
+    }
+    let cnonce = md5_hex(format!("{}{}{}{}", image.len(), image_md5, device.ip(), nonce).as_bytes());
+    socket.send(format!("{} {} {}\n", AUTH, cnonce, auth_response(password, nonce, &cnonce)).as_bytes())?;
+    match answer(&socket)?.as_str() {
+        "OK" => Ok(()),
+        reply => Err(format!("authentication failed: {}", reply).into()),
+    }
+}
+
+// This function waits for the invited device at `device` to connect back. Connections from other hosts are dropped, so
+// they cannot race the device for the image.
+fn accept(listener: &TcpListener, device: IpAddr, timeout: Duration) -> Result<TcpStream, Box<dyn Error>> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((stream, peer)) if peer.ip().to_canonical() == device.to_canonical() => {
+                stream.set_nonblocking(false)?;
+                return Ok(stream)
+            }
+            Ok((_, peer)) => warn!(peer = %peer, device = %device, "Dropped an espota connection from a host that was not invited."),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline =>
+                std::thread::sleep(Duration::from_millis(20)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Err("the device did not connect back".into()),
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+}
+
+// This function sends an image to a device following the espota protocol: a UDP invitation naming the TCP port of
+// `listener`, then the image over the connection the device opens to it. `progress` is called with the bytes the
+// device acknowledged so far.
+fn transfer(device: SocketAddr, listener: &TcpListener, image: &[u8], password: &str, timeout: Duration,
+            mut progress: impl FnMut(usize)) -> Result<(), Box<dyn Error>> {
+    invite(device, listener.local_addr()?.port(), image, password, timeout)?;
+    let mut stream = accept(listener, device.ip(), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut reply = [0u8; 32];
+    let mut answer = Vec::new();
+    let mut sent = 0;
+    for chunk in image.chunks(CHUNK) {
+        stream.write_all(chunk)?;
+        // The device answers every write to flash with the number of bytes written.
+        let len = stream.read(&mut reply)?;
+        if len == 0 {
+            return Err(format!("the device closed the connection after {} bytes", sent).into())
+        }
+        answer = reply[..len].to_vec();
+        sent += chunk.len();
+        progress(sent);
+    }
+    // The device checks the MD5 of the image and answers OK before it restarts, possibly together with its last count.
+    stream.set_read_timeout(Some(FINISH_TIMEOUT))?;
+    loop {
+        let text = String::from_utf8_lossy(&answer).to_string();
+        if text.contains("OK") {
+            return Ok(())
+        }
+        let len = stream.read(&mut reply)?;
+        if len == 0 {
+            return Err(format!("the device did not accept the image: {}", text.trim_start_matches(|c: char| c.is_ascii_digit())).into())
+        }
+        answer.extend_from_slice(&reply[..len]);
+    }
+}
+
+// This function prepares pushing the firmware of a device's target to it at `ip`. Only one push to a device may run at
+// a time. Returns the reason code when the push cannot be made.
+pub fn prepare(mac: &str, ip: IpAddr, port: Option<u16>) -> Result<Push, &'static str> {
+    let device = match find_device(mac) {
+        Some(device) => device,
+        _ => return Err("unknown_device")
+    };
+    let target = load_targets().unwrap_or_default().into_iter()
+        .find(|(m, _)| m.eq_ignore_ascii_case(mac))
+        .map(|(_, target)| target)
+        .unwrap_or(device.target_firmware);
+    let version = match latest_firmware_date(&target) {
+        Ok(version) if target != unassigned() => version,
+        _ => return Err("no_firmware")
+    };
+    let image = firmware::load_image(&target)?;
+    let mac = mac.to_uppercase();
+    if !ACTIVE.lock().unwrap().insert(mac.clone()) {
+        return Err("push_in_progress")
+    }
+    let device = SocketAddr::new(ip, port.unwrap_or(SETTINGS.espota.port));
+    Ok(Push { mac, device, target, version, bytes: image.len(), image })
+}
+
+impl Push {
+    // This function records a step of the push in the update history.
+    fn record(&self, result: UpdateResult, sent: usize, error: Option<String>) {
+        history::record(&UpdateRecord {
+            time: Utc::now(),
+            mac: self.mac.clone(),
+            target: self.target.clone(),
+            version: self.version,
+            method: UpdateMethod::Push,
+            result,
+            sent,
+            bytes: self.bytes,
+            error,
+        });
+    }
+
+    // This function pushes the firmware to the device, publishing its progress and recording it in the update history.
+    pub fn run(self) -> Result<(), Box<dyn Error>> {
+        let (mac, target, bytes) = (self.mac.as_str(), self.target.as_str(), self.bytes);
+        info!(mac, device = %self.device, target, bytes, "Pushing firmware over espota.");
+        events::publish(Event::PushStarted { mac, ip: &self.device.ip().to_string(), target, version: self.version, bytes });
+        self.record(UpdateResult::Started, 0, None);
+        let espota = &SETTINGS.espota;
+        let local: IpAddr = if self.device.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+        let mut acknowledged = 0;
+        let mut next_step = PROGRESS_STEP;
+        let result = TcpListener::bind((local, espota.host_port)).map_err(|e| e.into()).and_then(|listener| {
+            transfer(self.device, &listener, &self.image, &espota.password, Duration::from_secs(espota.timeout_secs), |sent| {
+                acknowledged = sent;
+                if sent < bytes && sent * 100 >= next_step * bytes {
+                    next_step = sent * 100 / bytes / PROGRESS_STEP * PROGRESS_STEP + PROGRESS_STEP;
+                    events::publish(Event::PushProgress { mac, target, sent, bytes });
+                    self.record(UpdateResult::InProgress, sent, None);
+                }
+            })
+        });
+        match &result {
+            Ok(_) => {
+                info!(mac, device = %self.device, target, bytes, "Pushed firmware over espota.");
+                events::publish(Event::PushCompleted { mac, target, bytes });
+                self.record(UpdateResult::Completed, bytes, None);
+            }
+            Err(e) => {
+                warn!(mac, device = %self.device, target, sent = acknowledged, error = %e, "Pushing firmware over espota failed.");
+                events::publish(Event::PushFailed { mac, target, sent: acknowledged, bytes, error: &e.to_string() });
+                self.record(UpdateResult::Failed, acknowledged, Some(e.to_string()));
+            }
+        }
+        result
+    }
+}
+
+impl Drop for Push {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.mac);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, auth_response, md5_hex, transfer, CHUNK};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    // This function starts a device speaking the ArduinoOTA side of espota on localhost. It returns the address it
+    // listens for invitations on and the image it received, if it accepted one. With `coalesce_ok` the device answers
+    // its last count and OK in one write, as devices flushing late do.
+    fn simulated_device(password: Option<&'static str>, coalesce_ok: bool) -> (SocketAddr, JoinHandle<Option<Vec<u8>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut buffer = [0u8; 256];
+            let (len, host) = socket.recv_from(&mut buffer).unwrap();
+            let invitation = String::from_utf8_lossy(&buffer[..len]).to_string();
+            let fields: Vec<&str> = invitation.split_whitespace().collect();
+            assert_eq!(fields[0], "0");
+            let (port, size, md5): (u16, usize, String) = (fields[1].parse().unwrap(), fields[2].parse().unwrap(), fields[3].to_string());
+            if let Some(password) = password {
+                socket.send_to(b"AUTH 0123456789abcdef", host).unwrap();
+                let (len, _) = socket.recv_from(&mut buffer).unwrap();
+                let answer = String::from_utf8_lossy(&buffer[..len]).to_string();
+                let fields: Vec<&str> = answer.split_whitespace().collect();
+                if fields[0] != "200" || fields[2] != auth_response(password, "0123456789abcdef", fields[1]) {
+                    socket.send_to(b"Authentication Failed", host).unwrap();
+                    return None
+                }
+            }
+            socket.send_to(b"OK", host).unwrap();
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut image = vec!();
+            // Like ArduinoOTA, counts are answered per chunk written to flash.
+            let mut chunk = [0u8; CHUNK];
+            while image.len() < size {
+                let len = CHUNK.min(size - image.len());
+                stream.read_exact(&mut chunk[..len]).unwrap();
+                image.extend_from_slice(&chunk[..len]);
+                if coalesce_ok && image.len() == size {
+                    assert_eq!(md5_hex(&image), md5);
+                    stream.write_all(format!("{}OK", len).as_bytes()).unwrap();
+                    return Some(image)
+                }
+                stream.write_all(len.to_string().as_bytes()).unwrap();
+            }
+            assert_eq!(md5_hex(&image), md5);
+            stream.write_all(b"OK").unwrap();
+            Some(image)
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn pushes_images_to_devices_that_authenticate() {
+        let image: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let (device, handle) = simulated_device(Some("secret"), false);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut progress = vec!();
+        transfer(device, &listener, &image, "secret", Duration::from_secs(5), |sent| progress.push(sent)).unwrap();
+        assert_eq!(handle.join().unwrap(), Some(image));
+        assert_eq!(progress, vec!(1460, 2920, 4380, 5000));
+    }
+
+    #[test]
+    fn accepts_the_ok_sent_with_the_last_count() {
+        let image: Vec<u8> = (0..3000u32).map(|i| (i % 241) as u8).collect();
+        let (device, handle) = simulated_device(None, true);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        transfer(device, &listener, &image, "", Duration::from_secs(5), |_| {}).unwrap();
+        assert_eq!(handle.join().unwrap(), Some(image));
+    }
+
+    #[test]
+    fn refuses_pushes_with_the_wrong_password() {
+        let (device, handle) = simulated_device(Some("secret"), false);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let error = transfer(device, &listener, b"image", "wrong", Duration::from_secs(5), |_| {}).unwrap_err();
+        assert!(error.to_string().starts_with("authentication failed"), "{}", error);
+        assert_eq!(handle.join().unwrap(), None);
+        let error = transfer(simulated_device(Some("secret"), false).0, &listener, b"image", "", Duration::from_secs(5), |_| {}).unwrap_err();
+        assert!(error.to_string().contains("requires a password"), "{}", error);
+    }
+
+    #[test]
+    fn only_accepts_the_invited_device() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut stranger = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let error = accept(&listener, "127.0.0.2".parse().unwrap(), Duration::from_millis(200)).unwrap_err();
+        assert_eq!(error.to_string(), "the device did not connect back");
+        assert_eq!(stranger.read(&mut [0u8; 1]).unwrap(), 0);
+        let _device = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        accept(&listener, "127.0.0.1".parse().unwrap(), Duration::from_secs(5)).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use actix_web::web::Bytes;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::Stream;
+use std::pin::Pin;
+use std::sync::{Mutex, Once};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::commands::{CommandKind, CommandState};
+use crate::fleet::UpdateStatus;
+use crate::history::{self, UpdateMethod, UpdateRecord, UpdateResult};
+use crate::remoteconfig::Scope;
+use crate::{mqtt, webhooks};
+
+// Events a subscriber may fall behind by before it is disconnected. EventSource clients reconnect on their own.
+const SUBSCRIBER_BUFFER: usize = 256;
+// How often subscribers are sent a comment, so proxies keep the stream open and closed streams are noticed.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// Size of the chunks firmware is written to the connection in.
+const DOWNLOAD_CHUNK: usize = 16 * 1024;
+
+lazy_static! {
+    // The open event streams.
+    static ref SUBSCRIBERS: Mutex<Vec<Sender<Bytes>>> = Mutex::new(vec!());
+}
+
+static KEEPALIVE: Once = Once::new();
+
+// Something that happened in the fleet, sent to `/api/v1/events` subscribers and webhooks as JSON with its `type` and
+// `time`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Check { mac: &'a str, ip: &'a str, running: DateTime<Utc>, status: UpdateStatus },
+    DownloadStarted { mac: &'a str, ip: &'a str, target: &'a str, version: DateTime<Utc>, bytes: usize },
+    DownloadCompleted { mac: &'a str, target: &'a str, bytes: usize },
+    DownloadAborted { mac: &'a str, target: &'a str, sent: usize, bytes: usize },
+    Rejected { status: u16, reason: &'a str, ip: &'a str, mac: Option<&'a str>, path: &'a str },
+    Registered { mac: &'a str },
+    Assigned { mac: &'a str, field: &'a str, value: &'a str },
+    FirmwareUploaded { target: &'a str, bytes: usize, signed: bool },
+    RolloutChanged { target: &'a str, percent: u8, paused: bool },
+    PushStarted { mac: &'a str, ip: &'a str, target: &'a str, version: DateTime<Utc>, bytes: usize },
+    PushProgress { mac: &'a str, target: &'a str, sent: usize, bytes: usize },
+    PushCompleted { mac: &'a str, target: &'a str, bytes: usize },
+    PushFailed { mac: &'a str, target: &'a str, sent: usize, bytes: usize, error: &'a str },
+    ConfigChanged { scope: Scope, name: &'a str, revision: u32 },
+    CommandQueued { mac: &'a str, id: &'a str, command: CommandKind },
+    CommandFinished { mac: &'a str, id: &'a str, command: CommandKind, state: CommandState },
+}
+
+impl Event<'_> {
+    // This function returns the `type` of the event, which webhooks filter on.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Check { .. } => "check",
+            Event::DownloadStarted { .. } => "download_started",
+            Event::DownloadCompleted { .. } => "download_completed",
+            Event::DownloadAborted { .. } => "download_aborted",
+            Event::Rejected { .. } => "rejected",
+            Event::Registered { .. } => "registered",
+            Event::Assigned { .. } => "assigned",
+            Event::FirmwareUploaded { .. } => "firmware_uploaded",
+            Event::RolloutChanged { .. } => "rollout_changed",
+            Event::PushStarted { .. } => "push_started",
+            Event::PushProgress { .. } => "push_progress",
+            Event::PushCompleted { .. } => "push_completed",
+            Event::PushFailed { .. } => "push_failed",
+            Event::ConfigChanged { .. } => "config_changed",
+            Event::CommandQueued { .. } => "command_queued",
+            Event::CommandFinished { .. } => "command_finished",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+// This function sends a frame to every subscriber, dropping those that are gone or too far behind.
+fn broadcast(frame: Bytes) {
+    SUBSCRIBERS.lock().unwrap().retain_mut(|subscriber| subscriber.try_send(frame.clone()).is_ok());
+}
+
+// This function sends an event to every open event stream and to the webhooks subscribing to it, and lets MQTT
+// subscribers know about firmware the event made available to them.
+pub fn publish(event: Event) {
+    mqtt::handle(&event);
+    let kind = event.kind();
+    let subscribed = !SUBSCRIBERS.lock().unwrap().is_empty();
+    if !subscribed && !webhooks::wants(kind) {
+        return
+    }
+    match serde_json::to_string(&Envelope { time: Utc::now(), event: &event }) {
+        Ok(json) => {
+            if subscribed {
+                broadcast(Bytes::from(format!("data: {}\n\n", json)));
+            }
+            webhooks::dispatch(kind, &json);
+        }
+        Err(e) => tracing::error!(error = %e, "Error serializing event."),
+    }
+}
+
+// This function opens a new event stream. The first subscriber starts the keepalive thread.
+pub fn subscribe() -> Receiver<Bytes> {
+    KEEPALIVE.call_once(|| {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(KEEPALIVE_INTERVAL);
+            broadcast(Bytes::from_static(b": keepalive\n\n"));
+        });
+    });
+    let (mut sender, receiver) = channel(SUBSCRIBER_BUFFER);
+    // Tell the client the stream is open before the first event arrives.
+    let _ = sender.try_send(Bytes::from_static(b": connected\n\n"));
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+// The body of a firmware download. It is written to the connection in chunks and publishes and records in the update
+// history whether the device received all of it or went away part way.
+pub struct DownloadBody {
+    data: Bytes,
+    sent: usize,
+    mac: String,
+    target: String,
+    version: DateTime<Utc>,
+}
+
+impl DownloadBody {
+    // This function starts a download and publishes it.
+    pub fn start(data: Vec<u8>, mac: &str, ip: &str, target: &str, version: DateTime<Utc>) -> DownloadBody {
+        publish(Event::DownloadStarted { mac, ip, target, version, bytes: data.len() });
+        let body = DownloadBody { data: Bytes::from(data), sent: 0, mac: mac.to_string(), target: target.to_string(), version };
+        body.record(UpdateResult::Started);
+        body
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    // This function records the state of the download in the update history.
+    fn record(&self, result: UpdateResult) {
+        history::record(&UpdateRecord {
+            time: Utc::now(),
+            mac: self.mac.clone(),
+            target: self.target.clone(),
+            version: self.version,
+            method: UpdateMethod::Download,
+            result,
+            sent: self.sent,
+            bytes: self.data.len(),
+            error: None,
+        });
+    }
+}
+
+impl Stream for DownloadBody {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let body = self.get_mut();
+        if body.sent == body.data.len() {
+            return Poll::Ready(None)
+        }
+        let end = (body.sent + DOWNLOAD_CHUNK).min(body.data.len());
+        let chunk = body.data.slice(body.sent..end);
+        body.sent = end;
+        if body.sent == body.data.len() {
+            publish(Event::DownloadCompleted { mac: &body.mac, target: &body.target, bytes: body.sent });
+            body.record(UpdateResult::Completed);
+        }
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+impl Drop for DownloadBody {
+    fn drop(&mut self) {
+        if self.sent < self.data.len() {
+            publish(Event::DownloadAborted { mac: &self.mac, target: &self.target, sent: self.sent, bytes: self.data.len() });
+            self.record(UpdateResult::Aborted);
+        }
+    }
+}
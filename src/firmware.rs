@@ -0,0 +1,155 @@
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use std::convert::TryFrom;
+use std::error::Error;
+
+use crate::settings::SETTINGS;
+use crate::{encryption, get_config_path, store};
+use tracing::{error, warn};
+
+lazy_static! {
+    // The key rota signs firmware with, loaded once from `firmware_signing.key_path`.
+    static ref SIGNING_KEY: Option<Keypair> = load_signing_key();
+}
+
+// This function loads the base64 encoded Ed25519 secret key from `firmware_signing.key_path`, if one is configured.
+fn load_signing_key() -> Option<Keypair> {
+    let path = &SETTINGS.firmware_signing.key_path;
+    if path.is_empty() {
+        return None
+    }
+    let loaded = std::fs::read_to_string(path).map_err(|e| e.to_string())
+        .and_then(|file| base64::decode(file.trim()).map_err(|e| e.to_string()))
+        .and_then(|bytes| SecretKey::from_bytes(&bytes).map_err(|e| e.to_string()));
+    match loaded {
+        Ok(secret) => {
+            let public = PublicKey::from(&secret);
+            Some(Keypair { secret, public })
+        }
+        Err(e) => panic!("Error loading firmware signing key {}, {}", path, e)
+    }
+}
+
+// This function generates a new signing key, writes it to `path` and returns the base64 encoded public key.
+pub fn generate_signing_key(path: &str) -> Result<String, Box<dyn Error>> {
+    let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+    match store::create_private(path, &base64::encode(keypair.secret.as_bytes())) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(format!("{} already exists", path).into()),
+        result => result?,
+    }
+    Ok(base64::encode(keypair.public.as_bytes()))
+}
+
+// This function returns the base64 encoded public key devices verify firmware with, if a signing key is configured.
+pub fn public_key() -> Option<String> {
+    SIGNING_KEY.as_ref().map(|keypair| base64::encode(keypair.public.as_bytes()))
+}
+
+// This function returns the keys whose signatures are accepted: rota's own and any keys of a CI pipeline.
+fn trusted_keys() -> Vec<PublicKey> {
+    let mut keys: Vec<PublicKey> = SIGNING_KEY.iter().map(|keypair| keypair.public).collect();
+    for key in SETTINGS.firmware_signing.trusted_public_keys.iter() {
+        match base64::decode(key.trim()).ok().and_then(|bytes| PublicKey::from_bytes(&bytes).ok()) {
+            Some(key) => keys.push(key),
+            _ => warn!(key = %key, "Ignoring invalid trusted firmware key."),
+        }
+    }
+    keys
+}
+
+// This function parses a base64 encoded signature.
+fn decode_signature(encoded: &str) -> Option<Signature> {
+    base64::decode(encoded.trim()).ok().and_then(|bytes| Signature::try_from(bytes.as_slice()).ok())
+}
+
+// This function returns the path of the signature stored next to a firmware binary.
+fn signature_path(target_path: &str) -> String {
+    format!("{}.sig", target_path)
+}
+
+// This function decides the signature stored with an uploaded binary. A signature supplied by CI must verify against a
+// trusted key, otherwise rota signs the binary itself. Returns `None` for unsigned firmware if signatures are optional.
+pub fn signature_for_upload(binary: &[u8], supplied: Option<&str>) -> Result<Option<Signature>, &'static str> {
+    match supplied {
+        Some(supplied) => match decode_signature(supplied) {
+            Some(signature) if trusted_keys().iter().any(|key| key.verify(binary, &signature).is_ok()) => Ok(Some(signature)),
+            Some(_) => Err("Firmware signature does not verify against a trusted key."),
+            _ => Err("Invalid esp-firmware-signature header."),
+        },
+        _ => match SIGNING_KEY.as_ref() {
+            Some(keypair) => Ok(Some(keypair.sign(binary))),
+            _ if SETTINGS.firmware_signing.required => Err("Unsigned firmware refused, send an esp-firmware-signature header."),
+            _ => Ok(None),
+        }
+    }
+}
+
+// This function stores the signature of a firmware binary, or removes a stale one when the binary is unsigned.
+pub fn save_signature(target_path: &str, signature: Option<&Signature>) -> std::io::Result<()> {
+    match signature {
+        Some(signature) => std::fs::write(signature_path(target_path), base64::encode(signature.to_bytes().as_ref())),
+        _ => match std::fs::remove_file(signature_path(target_path)) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+// This function returns the signature to serve with a firmware binary. A stored signature must still verify, so a
+// binary changed on disk is never served. Binaries copied in by hand are signed on the fly unless signatures are
+// required, then they have to be signed with `rota sign-firmware` first.
+pub fn signature_for_serving(target_path: &str, binary: &[u8]) -> Result<Option<Signature>, &'static str> {
+    if let Ok(stored) = std::fs::read_to_string(signature_path(target_path)) {
+        return match decode_signature(&stored) {
+            Some(signature) if trusted_keys().iter().any(|key| key.verify(binary, &signature).is_ok()) => Ok(Some(signature)),
+            _ => {
+                error!(target = %target_path, "Stored signature does not match the binary.");
+                Err("signature_mismatch")
+            }
+        }
+    }
+    match SIGNING_KEY.as_ref() {
+        _ if SETTINGS.firmware_signing.required => Err("unsigned_firmware"),
+        Some(keypair) => {
+            let signature = keypair.sign(binary);
+            if let Err(e) = save_signature(target_path, Some(&signature)) {
+                error!(target = %target_path, error = %e, "Error saving firmware signature.");
+            }
+            Ok(Some(signature))
+        }
+        _ => Ok(None),
+    }
+}
+
+// This function appends a signature the way ESP8266 signed updates lay it out: the signature followed by its length as
+// a little endian u32. The bootloader verifies the binary without this trailer.
+pub fn append_signature(mut binary: Vec<u8>, signature: &Signature) -> Vec<u8> {
+    let signature = signature.to_bytes();
+    binary.extend_from_slice(&signature);
+    binary.extend_from_slice(&(signature.len() as u32).to_le_bytes());
+    binary
+}
+
+// This function loads the image of a target for clients that take a plain binary without rota's headers, such as
+// ArduinoOTA and DDI clients: decrypted, with the signature appended if configured. Returns the reason code when the
+// firmware cannot be served.
+pub fn load_image(target: &str) -> Result<Vec<u8>, &'static str> {
+    let target_path = format!("{}{}", get_config_path(), target);
+    let mut image = match encryption::read_firmware(&format!("{}.ino.bin", target_path)) {
+        Ok(image) => image,
+        Err(e) => {
+            error!(target = %target, error = %e, "Error reading firmware.");
+            return Err("no_firmware")
+        }
+    };
+    if let Some(signature) = signature_for_serving(&target_path, &image)? {
+        if SETTINGS.firmware_signing.append_signature {
+            image = append_signature(image, &signature);
+        }
+    }
+    Ok(image)
+}
+
+// This function encodes a signature for the `x-rota-firmware-signature` header.
+pub fn encode_signature(signature: &Signature) -> String {
+    base64::encode(signature.to_bytes().as_ref())
+}
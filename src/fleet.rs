@@ -0,0 +1,233 @@
+use chrono::{DateTime, Duration, Utc};
+use std::error::Error;
+use std::sync::Mutex;
+
+use crate::events::{self, Event};
+use crate::rollout;
+use crate::{latest_firmware_date, load_deice_config, load_targets, store};
+
+lazy_static! {
+    // Serializes read-modify-write cycles on the device status list.
+    static ref DEVICE_STATUS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// The outcome of a device's last update check.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateStatus {
+    UpToDate,
+    UpdateAvailable,
+    FirmwareSent,
+    HeldByRollout,
+}
+
+// What a device reported in its last authenticated check, as stored in `device_status.toml`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceStatus {
+    pub mac: String,
+    pub version: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub last_ip: String,
+    pub status: UpdateStatus,
+}
+
+// The last DDI action a device closed, successfully or not. Actions are identified by the version they deploy.
+#[derive(Serialize, Deserialize)]
+struct ClosedAction {
+    mac: String,
+    version: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct DeviceStatusStore {
+    devices: Vec<DeviceStatus>,
+    #[serde(default)]
+    closed_actions: Vec<ClosedAction>,
+}
+
+// A registered device with its status, as listed on the dashboard.
+#[derive(Serialize)]
+pub struct FleetDevice {
+    pub mac: String,
+    pub alias: String,
+    pub group: String,
+    pub target: String,
+    pub latest_version: Option<DateTime<Utc>>,
+    pub reported_version: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub last_ip: Option<String>,
+    pub status: Option<UpdateStatus>,
+    pub cohort: u8,
+}
+
+// The device status list in the configuration directory.
+const DEVICE_STATUS: &str = "device_status.toml";
+
+// This function loads the last reported status of every device.
+fn load_device_status() -> Result<DeviceStatusStore, Box<dyn Error>> {
+    store::load(DEVICE_STATUS)
+}
+
+fn save_device_status(statuses: &DeviceStatusStore) -> Result<(), Box<dyn Error>> {
+    store::save(DEVICE_STATUS, statuses)
+}
+
+// This function checks whether a device's status differs from the stored one by more than being seen again within a
+// minute. Avoids rewriting the store on every single check from a busy fleet.
+fn changed(stored: &DeviceStatus, seen: &DeviceStatus) -> bool {
+    stored.version != seen.version || stored.last_ip != seen.last_ip || stored.status != seen.status
+        || seen.last_seen - stored.last_seen > Duration::minutes(1)
+}
+
+// This function records the outcome of an authenticated update check and publishes it.
+pub fn record_check(mac: &str, ip: &str, version: DateTime<Utc>, status: UpdateStatus) {
+    events::publish(Event::Check { mac, ip, running: version, status });
+    let _guard = DEVICE_STATUS_LOCK.lock().unwrap();
+    let result = load_device_status().and_then(|mut statuses| {
+        let seen = DeviceStatus { mac: mac.to_uppercase(), version, last_seen: Utc::now(), last_ip: ip.to_string(), status };
+        match statuses.devices.iter_mut().find(|d| d.mac.eq_ignore_ascii_case(mac)) {
+            Some(device) if !changed(device, &seen) => return Ok(()),
+            Some(device) => *device = seen,
+            _ => statuses.devices.push(seen),
+        }
+        save_device_status(&statuses)
+    });
+    if let Err(e) = result {
+        tracing::error!(error = %e, "Error saving device_status.toml.");
+    }
+}
+
+// This function records that a device polled without reporting its version, as DDI clients do. Only devices that
+// reported a version before are updated.
+pub fn record_poll(mac: &str, ip: &str, status: UpdateStatus) {
+    let _guard = DEVICE_STATUS_LOCK.lock().unwrap();
+    let result = load_device_status().and_then(|mut statuses| {
+        if let Some(device) = statuses.devices.iter_mut().find(|d| d.mac.eq_ignore_ascii_case(mac)) {
+            let seen = DeviceStatus { last_seen: Utc::now(), last_ip: ip.to_string(), status, ..device.clone() };
+            if changed(device, &seen) {
+                *device = seen;
+                save_device_status(&statuses)?;
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        tracing::error!(error = %e, "Error saving device_status.toml.");
+    }
+}
+
+// This function records a version a device reported outside of an update check, e.g. over MQTT. The device keeps its
+// last IP, and its status is worked out from the firmware of its target.
+pub fn record_report(mac: &str, version: DateTime<Utc>) -> Result<(), Box<dyn Error>> {
+    let target = load_targets()?.into_iter().find(|(m, _)| m.eq_ignore_ascii_case(mac)).map(|(_, target)| target);
+    let latest = target.and_then(|target| latest_firmware_date(&target).ok());
+    let status = match latest {
+        Some(latest) if version < latest => UpdateStatus::UpdateAvailable,
+        _ => UpdateStatus::UpToDate,
+    };
+    let _guard = DEVICE_STATUS_LOCK.lock().unwrap();
+    let mut statuses = load_device_status()?;
+    match statuses.devices.iter_mut().find(|d| d.mac.eq_ignore_ascii_case(mac)) {
+        Some(device) => {
+            device.version = version;
+            device.last_seen = Utc::now();
+            device.status = status;
+        }
+        _ => statuses.devices.push(DeviceStatus { mac: mac.to_uppercase(), version, last_seen: Utc::now(), last_ip: String::new(), status }),
+    }
+    save_device_status(&statuses)
+}
+
+// This function records that a device closed the DDI action deploying `version`, replacing the one it closed before.
+pub fn record_closed_action(mac: &str, version: DateTime<Utc>) -> Result<(), Box<dyn Error>> {
+    let _guard = DEVICE_STATUS_LOCK.lock().unwrap();
+    let mut statuses = load_device_status()?;
+    statuses.closed_actions.retain(|action| !action.mac.eq_ignore_ascii_case(mac));
+    statuses.closed_actions.push(ClosedAction { mac: mac.to_uppercase(), version });
+    save_device_status(&statuses)
+}
+
+// This function returns the version of the last DDI action a device closed.
+pub fn last_closed_action(mac: &str) -> Result<Option<DateTime<Utc>>, Box<dyn Error>> {
+    let _guard = DEVICE_STATUS_LOCK.lock().unwrap();
+    let statuses = load_device_status()?;
+    Ok(statuses.closed_actions.iter().find(|action| action.mac.eq_ignore_ascii_case(mac)).map(|action| action.version))
+}
+
+// This function picks the device with the MAC `mac` out of the fleet. The registry keeps MACs as they were registered,
+// so they are matched case-insensitively.
+pub fn find(devices: Vec<FleetDevice>, mac: &str) -> Option<FleetDevice> {
+    devices.into_iter().find(|d| d.mac.eq_ignore_ascii_case(mac))
+}
+
+// This function lists every registered device with the firmware it is assigned and what it last reported. The target
+// is the one in the `targets` file, which is what the device is served.
+pub fn list_fleet() -> Result<Vec<FleetDevice>, Box<dyn Error>> {
+    let statuses = {
+        let _guard = DEVICE_STATUS_LOCK.lock().unwrap();
+        load_device_status()?.devices
+    };
+    let targets = load_targets().unwrap_or_default();
+    Ok(load_deice_config()?.into_iter().map(|device| {
+        let status = statuses.iter().find(|s| s.mac.eq_ignore_ascii_case(&device.device_id));
+        let target = targets.iter().find(|(mac, _)| mac.eq_ignore_ascii_case(&device.device_id))
+            .map(|(_, target)| target.clone())
+            .unwrap_or(device.target_firmware);
+        FleetDevice {
+            latest_version: latest_firmware_date(&target).ok(),
+            reported_version: status.map(|s| s.version),
+            last_seen: status.map(|s| s.last_seen),
+            last_ip: status.map(|s| s.last_ip.clone()),
+            status: status.map(|s| s.status),
+            cohort: rollout::cohort(&device.device_id),
+            mac: device.device_id,
+            alias: device.device_alias,
+            group: device.device_group,
+            target,
+        }
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed, find, DeviceStatus, FleetDevice, UpdateStatus};
+    use chrono::{Duration, TimeZone, Utc};
+
+    fn device(mac: &str) -> FleetDevice {
+        FleetDevice {
+            mac: mac.to_string(),
+            alias: String::new(),
+            group: String::from("UNASSIGNED"),
+            target: String::from("fw1"),
+            latest_version: None,
+            reported_version: None,
+            last_seen: None,
+            last_ip: None,
+            status: None,
+            cohort: 0,
+        }
+    }
+
+    #[test]
+    fn finds_devices_registered_in_lower_case() {
+        let devices = || vec!(device("11:22:33:44:55:66"), device("aa:bb:cc:dd:ee:ff"));
+        assert_eq!(find(devices(), "AA:BB:CC:DD:EE:FF").map(|d| d.mac), Some(String::from("aa:bb:cc:dd:ee:ff")));
+        assert!(find(devices(), "AA:BB:CC:DD:EE:00").is_none());
+    }
+
+    #[test]
+    fn repeated_checks_are_only_saved_once_a_minute() {
+        let stored = DeviceStatus {
+            mac: String::from("AA:BB:CC:DD:EE:FF"),
+            version: Utc.ymd(2026, 10, 8).and_hms(12, 0, 5),
+            last_seen: Utc.ymd(2026, 10, 18).and_hms(12, 0, 0),
+            last_ip: String::from("192.0.2.1"),
+            status: UpdateStatus::UpToDate,
+        };
+        let seen_after = |secs| DeviceStatus { last_seen: stored.last_seen + Duration::seconds(secs), ..stored.clone() };
+        assert!(!changed(&stored, &seen_after(30)));
+        assert!(changed(&stored, &seen_after(61)));
+        assert!(changed(&stored, &DeviceStatus { status: UpdateStatus::UpdateAvailable, ..seen_after(30) }));
+        assert!(changed(&stored, &DeviceStatus { last_ip: String::from("192.0.2.2"), ..seen_after(30) }));
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use crate::{admin, get_config_path, keys, load_deice_config};
+
+// The result of the readiness checks served on `/readyz`.
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    // Each check with "ok" or the reason it failed.
+    pub checks: BTreeMap<&'static str, String>,
+}
+
+// Build information served on `/version`.
+#[derive(Serialize)]
+pub struct Version {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub profile: &'static str,
+}
+
+// This function checks that the device registry can be read.
+fn check_storage() -> Result<(), String> {
+    load_deice_config().map(|_| ()).map_err(|e| format!("device registry unreadable, {}", e))
+}
+
+// This function checks that the firmware directory and the targets file can be read.
+fn check_firmware() -> Result<(), String> {
+    let path = get_config_path();
+    std::fs::read_dir(&path).map_err(|e| format!("firmware directory {} unreadable, {}", path, e))?;
+    std::fs::read_to_string(format!("{}{}", path, "targets")).map_err(|e| format!("targets file unreadable, {}", e))?;
+    Ok(())
+}
+
+// This function checks that the device API keys and the admin tokens can be loaded. A missing admin tokens file only
+// disables the admin routes, so it does not make the server unready.
+fn check_keys() -> Result<(), String> {
+    keys::check_store().map_err(|e| format!("api key store unreadable, {}", e))?;
+    match admin::load_admin_tokens() {
+        Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!("admin tokens unreadable, {}", e)),
+        _ => Ok(()),
+    }
+}
+
+// This function runs every readiness check. The signing and encryption keys are not checked here, a broken key already
+// stops the server at startup.
+pub fn readiness() -> Readiness {
+    let mut checks = BTreeMap::new();
+    let mut ready = true;
+    for (name, check) in [("storage", check_storage as fn() -> Result<(), String>), ("firmware", check_firmware), ("keys", check_keys)].iter() {
+        let result = check();
+        ready &= result.is_ok();
+        checks.insert(*name, result.err().unwrap_or_else(|| String::from("ok")));
+    }
+    Readiness { ready, checks }
+}
+
+// This function returns the build information of this binary.
+pub fn version() -> Version {
+    Version {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("ROTA_GIT_COMMIT"),
+        profile: if cfg!(debug_assertions) { "debug" } else { "release" },
+    }
+}
@@ -0,0 +1,81 @@
+use chrono::{DateTime, Utc};
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use tracing::error;
+
+use crate::get_config_path;
+
+lazy_static! {
+    // Keeps lines from concurrent updates from interleaving.
+    static ref HISTORY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// How a device was sent firmware: downloaded by the device, pushed to it over espota, or reported by a DDI client in
+// its feedback on a deployment.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateMethod {
+    Download,
+    Push,
+    Ddi,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateResult {
+    Started,
+    InProgress,
+    Completed,
+    Aborted,
+    Failed,
+}
+
+// One step of an update, as written to `update_history.log`.
+#[derive(Serialize, Deserialize)]
+pub struct UpdateRecord {
+    pub time: DateTime<Utc>,
+    pub mac: String,
+    pub target: String,
+    pub version: DateTime<Utc>,
+    pub method: UpdateMethod,
+    pub result: UpdateResult,
+    // Bytes sent so far, of `bytes`.
+    pub sent: usize,
+    pub bytes: usize,
+    pub error: Option<String>,
+}
+
+// This function returns the path of the update history.
+fn history_path() -> String {
+    format!("{}{}", get_config_path(), "update_history.log")
+}
+
+// This function appends a step of an update to the history.
+pub fn record(record: &UpdateRecord) {
+    let _guard = HISTORY_LOCK.lock().unwrap();
+    let result = serde_json::to_string(record).map_err(|e| e.to_string()).and_then(|line| {
+        OpenOptions::new().create(true).append(true).open(history_path())
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        error!(error = %e, "Error writing update history.");
+    }
+}
+
+// This function lists the most recent update steps, oldest first, of every device or only of `mac`.
+pub fn recent_updates(mac: Option<&str>, limit: usize) -> Result<Vec<UpdateRecord>, Box<dyn Error>> {
+    let file = match std::fs::read_to_string(history_path()) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec!()),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let records: Vec<UpdateRecord> = file.lines()
+        .filter_map(|line| serde_json::from_str::<UpdateRecord>(line).ok())
+        .filter(|record| mac.map(|mac| record.mac.eq_ignore_ascii_case(mac)).unwrap_or(true))
+        .collect();
+    let start = records.len().saturating_sub(limit);
+    Ok(records.into_iter().skip(start).collect())
+}
@@ -0,0 +1,361 @@
+use chrono::{DateTime, Duration, Utc};
+use hmac::Hmac;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+
+use crate::credentials::{generate_secret, secret_matches};
+use crate::get_config_path;
+use crate::store;
+
+// How long a rotated key keeps working by default, giving devices time to receive the new key via OTA.
+pub const DEFAULT_ROTATION_OVERLAP_DAYS: i64 = 7;
+// The longest expiry or rotation overlap accepted, in days.
+pub const MAX_DAYS: i64 = 3650;
+// Key hashes are PBKDF2-HMAC-SHA256 stored as `pbkdf2-sha256$<rounds>$<hex>`, so a leaked key store is slow to brute
+// force. Hashes without the prefix are salted SHA-256 from older releases and are upgraded when the key is next used.
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const HASH_ROUNDS: u32 = 100_000;
+
+lazy_static! {
+    // Serializes read-modify-write cycles on the key store.
+    static ref KEY_STORE_LOCK: Mutex<()> = Mutex::new(());
+    // Keys that validated since startup, by the SHA-256 of the presented key, so busy devices do not pay for the slow
+    // hash on every request. Only holds keys that matched, and is checked against the store each time.
+    static ref VALIDATED_KEYS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+// A device API key as stored in `api_keys.toml`. Only the salted hash of the key is kept.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub label: String,
+    pub salt: String,
+    pub hash: String,
+    pub created: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<DateTime<Utc>>,
+    pub revoked: bool,
+}
+
+impl ApiKey {
+    // This function checks that the key is neither revoked nor expired.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        !self.revoked && self.expires.map(|e| now < e).unwrap_or(true)
+    }
+}
+
+// The metadata of a key that is safe to hand out through the admin API and CLI.
+#[derive(Serialize)]
+pub struct KeyInfo {
+    pub id: String,
+    pub label: String,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub revoked: bool,
+}
+
+impl From<&ApiKey> for KeyInfo {
+    fn from(k: &ApiKey) -> KeyInfo {
+        KeyInfo { id: k.id.clone(), label: k.label.clone(), created: k.created, expires: k.expires, last_used: k.last_used, revoked: k.revoked }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct KeyStore {
+    keys: Vec<ApiKey>,
+}
+
+// A freshly created key. The plaintext `secret` is only available at creation time.
+#[derive(Serialize)]
+pub struct IssuedKey {
+    pub id: String,
+    pub secret: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+// The hashed key store in the configuration directory.
+const KEY_STORE: &str = "api_keys.toml";
+
+// This function returns the path of the hashed key store.
+fn key_store_path() -> String {
+    format!("{}{}", get_config_path(), KEY_STORE)
+}
+
+// This function returns the path of the legacy plaintext key file.
+fn legacy_key_path() -> String {
+    format!("{}{}", get_config_path(), "api_keys")
+}
+
+// This function loads all keys from `api_keys.toml`, importing the legacy plaintext `api_keys` file on first use.
+fn load_keys() -> Result<Vec<ApiKey>, Box<dyn Error>> {
+    match std::fs::read_to_string(key_store_path()) {
+        Ok(file) => {
+            let store: KeyStore = toml::from_str(&file)?;
+            Ok(store.keys)
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let keys = import_legacy_keys()?;
+            if !keys.is_empty() {
+                save_keys(&keys)?;
+                tracing::warn!(keys = keys.len(), "Imported plaintext keys from api_keys into api_keys.toml. Delete api_keys once you have a backup.");
+            }
+            Ok(keys)
+        }
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+// This function parses a day count for a key expiry or rotation overlap, which must lie between 1 and `MAX_DAYS`.
+pub fn parse_days(days: &str) -> Result<i64, String> {
+    check_days(days.trim().parse().unwrap_or(0))
+}
+
+// This function checks that a day count lies between 1 and `MAX_DAYS`.
+fn check_days(days: i64) -> Result<i64, String> {
+    if (1..=MAX_DAYS).contains(&days) {
+        Ok(days)
+    } else {
+        Err(format!("day counts must lie between 1 and {}", MAX_DAYS))
+    }
+}
+
+// This function hashes every key of the legacy plaintext `api_keys` file into a key entry. Keys hold no whitespace, so
+// lines that do, like the notes in the example file, and lines starting with `#` are skipped.
+fn import_legacy_keys() -> Result<Vec<ApiKey>, Box<dyn Error>> {
+    let file = match std::fs::read_to_string(legacy_key_path()) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec!()),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let now = Utc::now();
+    let lines = file.lines().map(str::trim_end).filter(|l| !l.is_empty() && !l.starts_with('#') && !l.contains(char::is_whitespace));
+    Ok(lines.enumerate().map(|(i, line)| {
+        let salt = generate_secret(16);
+        ApiKey {
+            id: generate_secret(8),
+            label: format!("legacy-{}", i + 1),
+            hash: hash_key(&salt, line, HASH_ROUNDS),
+            salt,
+            created: now,
+            expires: None,
+            last_used: None,
+            revoked: false,
+        }
+    }).collect())
+}
+
+// This function hashes a key with its salt as hex encoded PBKDF2-HMAC-SHA256, prefixed with the scheme and rounds.
+fn hash_key(salt: &str, secret: &str, rounds: u32) -> String {
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(secret.as_bytes(), salt.as_bytes(), rounds, &mut hash);
+    format!("{}${}${}", HASH_SCHEME, rounds, hex::encode(hash))
+}
+
+// This function compares a presented key against the stored hash in constant time, accepting the older SHA-256 hashes.
+fn key_matches(key: &ApiKey, presented: &str) -> bool {
+    let mut parts = key.hash.splitn(3, '$');
+    match (parts.next(), parts.next().and_then(|rounds| rounds.parse().ok())) {
+        (Some(HASH_SCHEME), Some(rounds)) => {
+            hash_key(&key.salt, presented, rounds).as_bytes().ct_eq(key.hash.as_bytes()).into()
+        }
+        _ => secret_matches(&key.salt, &key.hash, presented),
+    }
+}
+
+// This function writes all keys into `api_keys.toml`, replacing the old copy.
+fn save_keys(keys: &[ApiKey]) -> Result<(), Box<dyn Error>> {
+    store::save(KEY_STORE, &KeyStore { keys: keys.to_vec() })
+}
+
+// This function checks that the key store can be read, without importing legacy keys or writing anything.
+pub fn check_store() -> Result<(), Box<dyn Error>> {
+    let _guard = KEY_STORE_LOCK.lock().unwrap();
+    store::load::<KeyStore>(KEY_STORE).map(|_| ())
+}
+
+// This function lists every key with its metadata.
+pub fn list_keys() -> Result<Vec<KeyInfo>, Box<dyn Error>> {
+    let _guard = KEY_STORE_LOCK.lock().unwrap();
+    Ok(load_keys()?.iter().map(KeyInfo::from).collect())
+}
+
+// This function creates a new key, optionally expiring after `expires_in_days`.
+pub fn create_key(label: &str, expires_in_days: Option<i64>) -> Result<IssuedKey, Box<dyn Error>> {
+    let expires = match expires_in_days {
+        Some(days) => Some(Utc::now() + Duration::days(check_days(days)?)),
+        _ => None,
+    };
+    let _guard = KEY_STORE_LOCK.lock().unwrap();
+    let mut keys = load_keys()?;
+    let issued = push_new_key(&mut keys, label, expires, Utc::now(), HASH_ROUNDS);
+    save_keys(&keys)?;
+    Ok(issued)
+}
+
+// This function generates a key created at `now`, appends it to `keys` and returns the plaintext.
+fn push_new_key(keys: &mut Vec<ApiKey>, label: &str, expires: Option<DateTime<Utc>>, now: DateTime<Utc>, rounds: u32)
+    -> IssuedKey {
+    let secret = generate_secret(48);
+    let salt = generate_secret(16);
+    let id = generate_secret(8);
+    keys.push(ApiKey {
+        id: id.clone(),
+        label: label.to_string(),
+        hash: hash_key(&salt, &secret, rounds),
+        salt,
+        created: now,
+        expires,
+        last_used: None,
+        revoked: false,
+    });
+    IssuedKey { id, secret, expires }
+}
+
+// This function replaces key `id` with a new key. The old key keeps working for `overlap_days` so devices can pick up the new one.
+pub fn rotate_key(id: &str, overlap_days: i64) -> Result<Option<IssuedKey>, Box<dyn Error>> {
+    let overlap_days = check_days(overlap_days)?;
+    let _guard = KEY_STORE_LOCK.lock().unwrap();
+    let mut keys = load_keys()?;
+    let issued = rotate(&mut keys, id, overlap_days, Utc::now(), HASH_ROUNDS);
+    if issued.is_some() {
+        save_keys(&keys)?;
+    }
+    Ok(issued)
+}
+
+// This function replaces the active key `id` in `keys` at `now`, letting the old key expire after `overlap_days` unless
+// it expires sooner anyway.
+fn rotate(keys: &mut Vec<ApiKey>, id: &str, overlap_days: i64, now: DateTime<Utc>, rounds: u32) -> Option<IssuedKey> {
+    let label = match keys.iter_mut().find(|k| k.id == id && k.is_active(now)) {
+        Some(old) => {
+            let overlap_end = now + Duration::days(overlap_days);
+            old.expires = Some(old.expires.map(|e| e.min(overlap_end)).unwrap_or(overlap_end));
+            old.label.clone()
+        }
+        _ => return None,
+    };
+    Some(push_new_key(keys, &label, None, now, rounds))
+}
+
+// This function revokes key `id` immediately. Returns false if no such key exists.
+pub fn revoke_key(id: &str) -> Result<bool, Box<dyn Error>> {
+    let _guard = KEY_STORE_LOCK.lock().unwrap();
+    let mut keys = load_keys()?;
+    let found = match keys.iter_mut().find(|k| k.id == id) {
+        Some(key) => {
+            key.revoked = true;
+            true
+        }
+        _ => false,
+    };
+    if found {
+        save_keys(&keys)?;
+    }
+    Ok(found)
+}
+
+// This function checks a presented key against every active key and records when it was last used. Keys still stored
+// with the older SHA-256 hash are rehashed.
+pub fn validate_key(presented: &str) -> Result<bool, Box<dyn Error>> {
+    let _guard = KEY_STORE_LOCK.lock().unwrap();
+    let mut keys = load_keys()?;
+    let now = Utc::now();
+    let digest = hex::encode(Sha256::digest(presented.as_bytes()));
+    let mut validated = VALIDATED_KEYS.lock().unwrap();
+    let known = validated.get(&digest).and_then(|id| keys.iter().position(|k| &k.id == id && k.is_active(now)));
+    let key = match known.or_else(|| find_active(&keys, presented, now)) {
+        Some(index) => &mut keys[index],
+        _ => return Ok(false),
+    };
+    validated.insert(digest, key.id.clone());
+    let mut changed = false;
+    if !key.hash.starts_with(&format!("{}$", HASH_SCHEME)) {
+        key.hash = hash_key(&key.salt, presented, HASH_ROUNDS);
+        changed = true;
+    }
+    // Avoid rewriting the store on every single request from a busy fleet.
+    if key.last_used.map(|t| now - t > Duration::minutes(1)).unwrap_or(true) {
+        key.last_used = Some(now);
+        changed = true;
+    }
+    if changed {
+        save_keys(&keys)?;
+    }
+    Ok(true)
+}
+
+// This function returns the index of the key in `keys` that matches `presented` and is active at `now`.
+fn find_active(keys: &[ApiKey], presented: &str, now: DateTime<Utc>) -> Option<usize> {
+    keys.iter().position(|k| k.is_active(now) && key_matches(k, presented))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_active, hash_key, key_matches, parse_days, push_new_key, rotate, ApiKey, MAX_DAYS};
+    use crate::credentials::hash_secret;
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn rotated_keys_overlap_then_expire() {
+        let now = Utc.ymd(2026, 10, 18).and_hms(12, 0, 0);
+        let mut keys = vec!();
+        let old = push_new_key(&mut keys, "lab", None, now, 1);
+        let new = rotate(&mut keys, &old.id, 7, now, 1).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1].label, "lab");
+        assert_eq!(keys[1].expires, None);
+        // Both keys work during the overlap, only the new one after it.
+        let active = |presented: &str, at| find_active(&keys, presented, at).map(|i| keys[i].id.clone());
+        assert_eq!(active(&old.secret, now + Duration::days(7) - Duration::seconds(1)), Some(old.id.clone()));
+        assert_eq!(active(&new.secret, now + Duration::days(7) - Duration::seconds(1)), Some(new.id.clone()));
+        assert_eq!(active(&old.secret, now + Duration::days(7)), None);
+        assert_eq!(active(&new.secret, now + Duration::days(7)), Some(new.id.clone()));
+        // An expired key cannot be rotated again.
+        assert!(rotate(&mut keys, &old.id, 7, now + Duration::days(7), 1).is_none());
+    }
+
+    #[test]
+    fn rotation_keeps_an_earlier_expiry() {
+        let now = Utc.ymd(2026, 10, 18).and_hms(12, 0, 0);
+        let mut keys = vec!();
+        let old = push_new_key(&mut keys, "lab", Some(now + Duration::days(2)), now, 1);
+        rotate(&mut keys, &old.id, 7, now, 1).unwrap();
+        assert_eq!(keys[0].expires, Some(now + Duration::days(2)));
+    }
+
+    #[test]
+    fn day_counts_are_bounded() {
+        assert_eq!(parse_days(" 7 "), Ok(7));
+        assert_eq!(parse_days(&MAX_DAYS.to_string()), Ok(MAX_DAYS));
+        for days in &["0", "-1", "seven", &(MAX_DAYS + 1).to_string(), "99999999999999999999"] {
+            assert!(parse_days(days).is_err(), "{}", days);
+        }
+    }
+
+    #[test]
+    fn older_sha256_hashes_still_match() {
+        let mut key = ApiKey {
+            id: "id".to_string(),
+            label: "legacy-1".to_string(),
+            salt: "salt".to_string(),
+            hash: hash_secret("salt", "secret"),
+            created: Utc.ymd(2026, 10, 18).and_hms(12, 0, 0),
+            expires: None,
+            last_used: None,
+            revoked: false,
+        };
+        assert!(key_matches(&key, "secret"));
+        assert!(!key_matches(&key, "guess"));
+        key.hash = hash_key("salt", "secret", 2);
+        assert!(key.hash.starts_with("pbkdf2-sha256$2$"));
+        assert!(key_matches(&key, "secret"));
+        assert!(!key_matches(&key, "guess"));
+    }
+}
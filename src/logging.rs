@@ -0,0 +1,142 @@
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::io::IsTerminal;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::{field, info_span, Instrument, Span};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+
+use crate::metrics;
+use crate::proxy::ClientInfo;
+use crate::settings::SETTINGS;
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+// This function builds an output layer in the configured format, `human` or `json`.
+fn output_layer<W>(writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    match SETTINGS.logging.format.as_str() {
+        "json" => fmt::layer().json().with_current_span(true).with_span_list(false).with_writer(writer).boxed(),
+        _ => fmt::layer().with_ansi(ansi).with_writer(writer).boxed(),
+    }
+}
+
+// This function installs the global logger from the `[logging]` settings. The `RUST_LOG` environment variable overrides
+// the configured filter. The returned guard flushes the log file and must be held until the server exits.
+pub fn init() -> Option<WorkerGuard> {
+    let logging = &SETTINGS.logging;
+    let filter = EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(&logging.filter)).unwrap_or_else(|e| {
+        eprintln!("Invalid logging filter {}, using info. {}", logging.filter, e);
+        EnvFilter::new("info")
+    });
+    let mut layers: Vec<BoxedLayer> = vec!();
+    if logging.stdout {
+        // Only colour the output when someone is watching it.
+        layers.push(output_layer(std::io::stdout, std::io::stdout().is_terminal()));
+    }
+    let mut guard = None;
+    if !logging.directory.is_empty() {
+        let rotation = match logging.rotation.as_str() {
+            "minutely" => Rotation::MINUTELY,
+            "hourly" => Rotation::HOURLY,
+            "never" => Rotation::NEVER,
+            _ => Rotation::DAILY,
+        };
+        let (writer, file_guard) = tracing_appender::non_blocking(RollingFileAppender::new(rotation, &logging.directory, "rota.log"));
+        layers.push(output_layer(writer, false));
+        guard = Some(file_guard);
+    }
+    tracing_subscriber::registry().with(layers).with(filter).init();
+    guard
+}
+
+// This function records the MAC of the device a request came from on the request span.
+pub fn record_mac(mac: &str) {
+    Span::current().record("mac", mac);
+}
+
+// Middleware giving every request a span carrying its request ID, client IP, method and route, and logging its status
+// and latency when it completes. The latency is also recorded in the metrics. The request ID is returned in the
+// `x-request-id` header.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S> for RequestTracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware { service })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestTracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let request_id = format!("{:016x}", rand::random::<u64>());
+        let span = info_span!("request",
+                              request_id = %request_id,
+                              ip = %ClientInfo::of_service_request(&req).ip_string(),
+                              method = %req.method(),
+                              route = %req.path(),
+                              mac = field::Empty);
+        let started = Instant::now();
+        let future = {
+            let _entered = span.enter();
+            self.service.call(req)
+        };
+        Box::pin(async move {
+            let result = future.await;
+            let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+            match result {
+                Ok(mut res) => {
+                    tracing::info!(status = res.status().as_u16(), latency_ms, "Request completed.");
+                    metrics::observe_request(&metrics::route_label(res.request()), res.status().as_u16(), latency_ms / 1000.0);
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        res.headers_mut().insert(HeaderName::from_static("x-request-id"), value);
+                    }
+                    Ok(res)
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, latency_ms, "Request failed.");
+                    metrics::observe_request("other", 500, latency_ms / 1000.0);
+                    Err(e)
+                }
+            }
+        }.instrument(span))
+    }
+}
@@ -1,28 +1,79 @@
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate lazy_static;
 
 extern crate actix_web;
 extern crate chrono;
 extern crate config;
 extern crate dirs;
 
-use actix_web::{HttpServer, App, web, HttpRequest, HttpResponse, Responder};
+mod admin;
+mod cli;
+mod commands;
+mod credentials;
+mod ddi;
+mod encryption;
+mod espota;
+mod events;
+mod firmware;
+mod fleet;
+mod health;
+mod history;
+mod keys;
+mod logging;
+mod mdns;
+mod metrics;
+mod mqtt;
+mod policy;
+mod proxy;
+mod ratelimit;
+mod remoteconfig;
+mod rollout;
+mod securitylog;
+mod settings;
+mod signing;
+mod store;
+mod tls;
+mod webhooks;
+
+use actix_web::{App, web, HttpRequest, HttpResponse, Responder};
+use actix_web::body::{Body, SizedStream};
+use actix_web::dev::AppConfig;
+use actix_web::middleware::Condition;
+use actix_http::HttpService;
+use actix_service::map_config;
 use std::io;
 use chrono::{DateTime, Utc, TimeZone};
 use actix_web::http::{StatusCode, HeaderMap};
-use std::io::{Read, Write};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use std::io::Write;
 use std::str;
+use futures::StreamExt;
 use config::{Config};
 use std::convert::From;
 use std::path::Path;
 use std::fs::File;
 use std::error::Error;
+use admin::{AdminAuth, AdminScope};
+use settings::SETTINGS;
+use sha2::{Digest, Sha256};
+use proxy::ClientInfo;
+use tracing::{debug, error, info, warn};
+use ratelimit::ClientKind;
+use fleet::UpdateStatus;
 
 #[derive(Serialize, Deserialize)]
 struct EspDevice {
     device_id: String,
     device_alias: String,
-    target_firmware: String
+    target_firmware: String,
+    #[serde(default = "unassigned")]
+    device_group: String
+}
+// This function returns the placeholder stored for unassigned device fields.
+fn unassigned() -> String {
+    String::from("UNASSIGNED")
 }
 // This function generates a string representing the path to the configuration file.
 fn get_config_path() -> String {
@@ -36,108 +87,206 @@ fn get_config_path() -> String {
 // The main OTA function, handles route /ota
 async fn ota(req: HttpRequest) -> impl Responder {
     // Get the headers from the request.
-    let headers: &HeaderMap = &req.headers();
+    let headers: &HeaderMap = req.headers();
     // Before doing anything, authenticate the api key and device type.
-    if !check_device_is_allowed(&headers) {
-        // Device is not allowed, send 403 Forbidden. Print IP if it exists. Fail2ban?
-        match headers.get("x-real-ip") {
-            Some(ip) => println!("Device with IP {} rejected.", ip.to_str().unwrap()),
-            _ => {}
-        };
-        return HttpResponse::Forbidden().finish()
-    }
-    if !validate_api_key(&headers) {
-        // API key not recognized, send 401 Unauthorized.
-        match headers.get("x-real-ip") {
-            Some(ip) => println!("Device with IP {} failed to authenticate.", ip.to_str().unwrap()),
-            _ => {}
-        };
-        return HttpResponse::Unauthorized().finish()
-    }
+    let (client_ip, client_mac) = match admit_device(&req) {
+        Ok(client) => client,
+        Err(refused) => return refused
+    };
     // Handle OTA request if client bears key and is esp32/8266
     let mac_addr = extract_mac_addr_string(headers);
-    let firmware_version_str = extract_firmware_string(headers);
-    println!("Device ID {} validated with api key.", mac_addr);
-    // Warn if device is sending API key over an unencrypted HTTP connection, if the header is found that is.
-    match client_using_https(&headers) {
-        false => println!("WARNING: Client {} is sending API key over an unencrypted HTTP request.", mac_addr),
-        _ => {}
-    };
-    let firmware_version = extract_version_from_version_str(firmware_version_str.as_str());
+    let DeviceFirmware { running: firmware_version, target_path, target, latest } = match device_firmware(&req) {
+        Ok(firmware) => firmware,
+        Err(refused) => return refused
+    };
+    metrics::record_device_version(&mac_addr, &version_label(&firmware_version));
+    if firmware_version.timestamp() < latest.timestamp() && !rollout::includes(&target, &client_mac) {
+        return hold_for_rollout(&req, &client_ip, &target, firmware_version)
+    }
     // If the headers contain the version number then continue parsing update...
-    if firmware_version.timestamp() < get_latest_firmware_date(headers).timestamp() {
-        let mut buffer:Vec<u8> = Vec::new();
-        if let Ok(mut f) = std::fs::File::open(std::path::Path::new(format!("{}.ino.bin", construct_target_firmware_path_string(headers)).as_str())) {
-            match f.read_to_end(buffer.as_mut()) {
-                Ok(_) => {},
-                Err(e) => panic!("Error reading binary file file, {}", e)
+    if firmware_version.timestamp() < latest.timestamp() {
+        // Firmware may be stored encrypted, it is decrypted here and never written back in plaintext.
+        let mut buffer: Vec<u8> = match encryption::read_firmware(&format!("{}.ino.bin", target_path)) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                error!(target = %target_path, error = %e, "Error reading firmware binary.");
+                return HttpResponse::ServiceUnavailable().header("x-rota-reason", "no_firmware").body("no_firmware")
             }
-        } else {
-            panic!("Error opening binary file.");
-        }
-        match headers.get("x-esp8266-sta-mac") {
-            Some(mac) => println!("Sending firmware dated {} to ESP8266 {} running firmware dated {}", get_latest_firmware_date(headers), mac.clone().to_str().unwrap(), firmware_version),
-            _ => match headers.get("x-esp32-sta-mac") {
-                Some(mac) => println!("Sending firmware dated {} to ESP32 {} running firmware dated {}", get_latest_firmware_date(headers), mac.clone().to_str().unwrap(), firmware_version),
-                _ => println!("Device not recognized?")
+        };
+        // Devices verify the signature before committing the update.
+        let mut response = HttpResponse::build(StatusCode::from_u16(200).unwrap());
+        response.header("x-rota-firmware-sha256", hex::encode(Sha256::digest(&buffer)));
+        match firmware::signature_for_serving(&target_path, &buffer) {
+            Ok(Some(signature)) => {
+                response.header("x-rota-firmware-signature", firmware::encode_signature(&signature))
+                    .header("x-rota-signature-algorithm", "ed25519");
+                if SETTINGS.firmware_signing.append_signature {
+                    buffer = firmware::append_signature(buffer, &signature);
+                }
+            }
+            Ok(None) => {},
+            Err(reason) => {
+                error!(decision = "refuse", reason, target = %target_path, "Refusing to send firmware.");
+                return HttpResponse::ServiceUnavailable().header("x-rota-reason", reason).body(reason)
             }
         }
-        HttpResponse::build(StatusCode::from_u16(200).unwrap()).body(buffer)
-    } else {
-        match headers.get("x-esp8266-sta-mac") {
-            Some(mac) => println!("ESP8266 {} running latest firmware already.", mac.clone().to_str().unwrap()),
-            _ => match headers.get("x-esp32-sta-mac") {
-                Some(mac) => println!("ESP32 {} running latest firmware already.", mac.clone().to_str().unwrap()),
-                _ => println!("Device not recognized?")
+        // Devices with a delivery key of their own or of their group receive an `esp_encrypted_img` image.
+        let group = find_device(&mac_addr).map(|device| device.device_group);
+        match encryption::encrypt_for_device(&mac_addr, group.as_deref(), &buffer) {
+            Ok(Some(image)) => {
+                response.header("x-rota-encryption", "esp_encrypted_img");
+                buffer = image;
+            }
+            Ok(None) => {},
+            Err(e) => {
+                error!(mac = %mac_addr, error = %e, "Error encrypting firmware for device.");
+                return HttpResponse::InternalServerError().finish()
             }
         }
+        info!(decision = "send_firmware", device = device_type(headers), target = %target_path, latest = %latest,
+              running = %firmware_version, bytes = buffer.len(), "Sending firmware.");
+        metrics::record_check(req.path(), true);
+        metrics::record_download(&target, &version_label(&latest), buffer.len());
+        fleet::record_check(&client_mac, &client_ip, firmware_version, UpdateStatus::FirmwareSent);
+        // Sized, so the device still gets the Content-Length header it needs to prepare the flash.
+        let body = events::DownloadBody::start(buffer, &client_mac, &client_ip, &target, latest);
+        response.body(Body::from_message(SizedStream::new(body.len() as u64, body)))
+    } else {
+        info!(decision = "up_to_date", device = device_type(headers), running = %firmware_version, "Device running latest firmware already.");
+        metrics::record_check(req.path(), false);
+        fleet::record_check(&client_mac, &client_ip, firmware_version, UpdateStatus::UpToDate);
         HttpResponse::NotModified().finish()
     }
 }
 // This function checks to see if the device is running an outdated version of the firmware.
 async fn check_for_firmware_update(req: HttpRequest) -> impl Responder {
     // Get the headers from the request.
-    let headers: &HeaderMap = &req.headers();
+    let headers: &HeaderMap = req.headers();
     // Before doing anything, authenticate the api key and device type.
-    if !check_device_is_allowed(&headers) {
-        // Device is not allowed, send 403 Forbidden. Print IP if it exists. Fail2ban?
-        match headers.get("x-real-ip") {
-            Some(ip) => println!("Device with IP {} rejected.", ip.to_str().unwrap()),
-            _ => {}
-        };
-        return HttpResponse::Forbidden().finish()
-    }
-    if !validate_api_key(&headers) {
-        // API key not recognized, send 401 Unauthorized.
-        match headers.get("x-real-ip") {
-            Some(ip) => println!("Device with IP {} failed to authenticate.", ip.to_str().unwrap()),
-            _ => {}
-        };
-        return HttpResponse::Unauthorized().finish()
-    }
-    let firmware_version_str = extract_firmware_string(headers);
-    let version = extract_version_from_version_str(firmware_version_str.as_ref());
-    let latest = get_latest_firmware_date(headers);
-    if version.timestamp() < latest.timestamp() {
+    let (client_ip, client_mac) = match admit_device(&req) {
+        Ok(client) => client,
+        Err(refused) => return refused
+    };
+    let DeviceFirmware { running: version, target, latest, .. } = match device_firmware(&req) {
+        Ok(firmware) => firmware,
+        Err(refused) => return refused
+    };
+    metrics::record_device_version(&client_mac, &version_label(&version));
+    let mut response = if version.timestamp() < latest.timestamp() && !rollout::includes(&target, &client_mac) {
+        hold_for_rollout(&req, &client_ip, &target, version)
+    } else if version.timestamp() < latest.timestamp() {
+        info!(decision = "update_available", device = device_type(headers), latest = %latest, running = %version, "Update available.");
+        metrics::record_check(req.path(), true);
+        fleet::record_check(&client_mac, &client_ip, version, UpdateStatus::UpdateAvailable);
         HttpResponse::Ok().finish()
     } else {
+        info!(decision = "up_to_date", device = device_type(headers), running = %version, "Device running latest firmware already.");
+        metrics::record_check(req.path(), false);
+        fleet::record_check(&client_mac, &client_ip, version, UpdateStatus::UpToDate);
         HttpResponse::NotModified().finish()
+    };
+    // Devices only fetch `/commands` when told here that some are waiting.
+    let pending = commands::pending_count(&client_mac);
+    if pending > 0 {
+        response.headers_mut().insert(HeaderName::from_static("x-rota-commands"), HeaderValue::from(pending));
+    }
+    response
+}
+// This function serves a device the configuration merged from the layers of its target, group and device, or 304 Not
+// Modified if the version it reports in the `x-rota-config-version` header is current.
+async fn get_config(req: HttpRequest) -> impl Responder {
+    let (client_ip, client_mac) = match admit_device(&req) {
+        Ok(client) => client,
+        Err(refused) => return refused
+    };
+    let device = match fleet::list_fleet().map(|devices| fleet::find(devices, &client_mac)) {
+        Ok(Some(device)) => device,
+        Ok(None) => {
+            warn!(decision = "reject", reason = "unknown_device", "Configuration requested by an unregistered device.");
+            return HttpResponse::NotFound().header("x-rota-reason", "unknown_device").body("unknown_device")
+        }
+        Err(e) => {
+            error!(error = %e, "Error loading fleet.");
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+    let config = match remoteconfig::config_for(&device) {
+        Ok(config) => config,
+        Err(e) => {
+            error!(error = %e, "Error loading device_config.toml.");
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+    let reported = req.headers().get("x-rota-config-version").and_then(|h| h.to_str().ok()).map(str::trim);
+    if reported == Some(config.version.as_str()) {
+        debug!(decision = "config_current", ip = %client_ip, version = %config.version, "Device configuration is current.");
+        return HttpResponse::NotModified().header("x-rota-config-version", config.version).finish()
+    }
+    info!(decision = "send_config", ip = %client_ip, version = %config.version, reported = ?reported, "Sending device configuration.");
+    HttpResponse::Ok().header("x-rota-config-version", config.version.as_str()).json(config)
+}
+// This function hands a device the commands waiting for it, oldest first.
+async fn get_commands(req: HttpRequest) -> impl Responder {
+    let (_, client_mac) = match admit_device(&req) {
+        Ok(client) => client,
+        Err(refused) => return refused
+    };
+    match commands::take_pending(&client_mac) {
+        Ok(pending) => {
+            if !pending.is_empty() {
+                info!(decision = "send_commands", commands = pending.len(), "Sending commands.");
+            }
+            HttpResponse::Ok().json(pending)
+        }
+        Err(e) => {
+            error!(error = %e, "Error saving commands.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function records a device's acknowledgement of the command in the `x-rota-command-id` header. The
+// `x-rota-command-result` header is `done` or `failed`, the body an optional message.
+async fn ack_command(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    let (_, client_mac) = match admit_device(&req) {
+        Ok(client) => client,
+        Err(refused) => return refused
+    };
+    let headers: &HeaderMap = req.headers();
+    let id = match headers.get("x-rota-command-id").and_then(|h| h.to_str().ok()) {
+        Some(id) => id.trim().to_string(),
+        _ => return HttpResponse::BadRequest().body("Missing x-rota-command-id header.")
+    };
+    let success = match headers.get("x-rota-command-result").and_then(|h| h.to_str().ok()) {
+        Some("done") => true,
+        Some("failed") => false,
+        _ => return HttpResponse::BadRequest().body("x-rota-command-result must be done or failed.")
+    };
+    let message = String::from_utf8_lossy(&body).trim().chars().take(1024).collect::<String>();
+    match commands::acknowledge(&client_mac, &id, success, Some(message).filter(|m| !m.is_empty())) {
+        Ok(Some(command)) => {
+            info!(id = %id, command = ?command.command, state = ?command.state, "Device acknowledged command.");
+            events::publish(events::Event::CommandFinished { mac: &client_mac, id: &id, command: command.command, state: command.state });
+            HttpResponse::Ok().body(String::from("Acknowledged."))
+        }
+        Ok(None) => HttpResponse::NotFound().body(String::from("No unfinished command with this id.")),
+        Err(e) => {
+            error!(error = %e, "Error saving commands.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
     }
 }
+// This function answers a device whose update is held back by the rollout of its target as if it were up to date.
+fn hold_for_rollout(req: &HttpRequest, client_ip: &str, target: &str, running: DateTime<Utc>) -> HttpResponse {
+    let mac = extract_mac_addr_string(req.headers()).to_uppercase();
+    info!(decision = "held_by_rollout", target, running = %running, "Update held back by rollout.");
+    metrics::record_check(req.path(), false);
+    fleet::record_check(&mac, client_ip, running, UpdateStatus::HeldByRollout);
+    HttpResponse::NotModified().finish()
+}
 // This function is used to register devices via mac address. Saves to configuration file.
 async fn register_device(req: HttpRequest) -> impl Responder {
-    // Get the headers from the request.
-    let headers: &HeaderMap = &req.headers();
-    // Before doing anything, authenticate the api key.
-    if !validate_api_key(&headers) {
-        // API key not recognized, send 401 Unauthorized.
-        match headers.get("x-real-ip") {
-            Some(ip) => println!("Device with IP {} failed to authenticate.", ip.to_str().unwrap()),
-            _ => {}
-        };
-        return HttpResponse::Unauthorized().finish()
-    }
-
+    // Get the headers from the request. Admin credentials are checked by the `AdminAuth` middleware.
+    let headers: &HeaderMap = req.headers();
     // Write mac address into configuration file.
     if let Some(header) = headers.get("esp-device-id") {
         let esp_id = match header.to_str() {
@@ -148,25 +297,34 @@ async fn register_device(req: HttpRequest) -> impl Responder {
         let device_to_save = EspDevice {
             device_id: esp_id.parse().unwrap(),
             device_alias: String::from("UNASSIGNED"),
-            target_firmware: String::from("UNASSIGNED")
+            target_firmware: String::from("UNASSIGNED"),
+            device_group: unassigned()
         };
-        save_settings(device_to_save)
+        save_settings(device_to_save);
+        info!(mac = esp_id, "Registered device.");
+        events::publish(events::Event::Registered { mac: esp_id });
+        // Optionally issue a secret bound to this device. The plaintext is only ever returned here.
+        if let Some(issue) = headers.get("esp-issue-secret") {
+            if issue.to_str().map(|v| v == "true").unwrap_or(false) {
+                return match credentials::issue_device_secret(esp_id) {
+                    Ok(secret) => match signing::device_signing_key(esp_id) {
+                        Some(signing_key) => HttpResponse::Ok().body(format!("Wrote device into settings.\n{}\n{}", secret, signing_key)),
+                        _ => HttpResponse::Ok().body(format!("Wrote device into settings.\n{}", secret))
+                    },
+                    Err(e) => {
+                        error!(mac = esp_id, error = %e, "Error saving device secret.");
+                        HttpResponse::InternalServerError().finish()
+                    }
+                }
+            }
+        }
     }
     HttpResponse::Ok().body(String::from("Wrote device into settings."))
 }
 // This function is used to assign a target firmware to a device via device id. Saves to configuration file.
 async fn assign_firmware(req: HttpRequest) -> impl Responder {
-    // Get the headers from the request.
-    let headers: &HeaderMap = &req.headers();
-    // Before doing anything, authenticate the api key.
-    if !validate_api_key(&headers) {
-        // API key not recognized, send 401 Unauthorized.
-        match headers.get("x-real-ip") {
-            Some(ip) => println!("Device with IP {} failed to authenticate.", ip.to_str().unwrap()),
-            _ => {}
-        };
-        return HttpResponse::Unauthorized().finish()
-    }
+    // Get the headers from the request. Admin credentials are checked by the `AdminAuth` middleware.
+    let headers: &HeaderMap = req.headers();
     // Write target target firmware into configuration file.
     if let Some(header) = headers.get("esp-device-id") {
         let esp_id = match header.to_str() {
@@ -175,7 +333,8 @@ async fn assign_firmware(req: HttpRequest) -> impl Responder {
         };
         if let Some(header) = headers.get("esp-target-firmware") {
             let esp_firmware = match header.to_str() {
-                Ok(firmware) => firmware,
+                Ok(firmware) if !firmware.is_empty() && !firmware.contains("..") => firmware,
+                Ok(_) => return HttpResponse::BadRequest().body("Invalid esp-target-firmware header."),
                 Err(e) => panic!("Device ID is invalid {}", e)
             };
             // Load device configuration file into memory
@@ -184,27 +343,27 @@ async fn assign_firmware(req: HttpRequest) -> impl Responder {
             let device_to_save = EspDevice {
                 device_id: devices.get(dev_index).unwrap().device_id.to_string(),
                 device_alias: devices.get(dev_index).unwrap().device_alias.to_string(),
-                target_firmware: esp_firmware.to_string()
+                target_firmware: esp_firmware.to_string(),
+                device_group: devices.get(dev_index).unwrap().device_group.to_string()
             };
             purge_device_by_index(dev_index);
             save_settings(device_to_save);
+            // The `targets` file decides what the device is served.
+            let target = remove_whitespace(esp_firmware);
+            if let Err(e) = save_target(esp_id, &target) {
+                error!(mac = esp_id, error = %e, "Error saving targets file.");
+                return HttpResponse::InternalServerError().finish()
+            }
+            info!(mac = esp_id, target = %target, "Assigned firmware to device.");
+            events::publish(events::Event::Assigned { mac: esp_id, field: "target", value: &target });
         }
     }
     HttpResponse::Ok().body(String::from("Assigned firmware to device."))
 }
 // This function is used to assign an alias to a device via device id. Saves to configuration file.
 async fn assign_alias(req: HttpRequest) -> impl Responder {
-    // Get the headers from the request.
-    let headers: &HeaderMap = &req.headers();
-    // Before doing anything, authenticate the api key.
-    if !validate_api_key(&headers) {
-        // API key not recognized, send 401 Unauthorized.
-        match headers.get("x-real-ip") {
-            Some(ip) => println!("Device with IP {} failed to authenticate.", ip.to_str().unwrap()),
-            _ => {}
-        };
-        return HttpResponse::Unauthorized().finish()
-    }
+    // Get the headers from the request. Admin credentials are checked by the `AdminAuth` middleware.
+    let headers: &HeaderMap = req.headers();
     // Write target target firmware into configuration file.
     if let Some(header) = headers.get("esp-device-id") {
         let esp_id = match header.to_str() {
@@ -222,19 +381,447 @@ async fn assign_alias(req: HttpRequest) -> impl Responder {
             let device_to_save = EspDevice {
                 device_id: devices.get(dev_index).unwrap().device_id.to_string(),
                 device_alias: esp_alias.to_string(),
-                target_firmware: devices.get(dev_index).unwrap().device_id.to_string()
+                target_firmware: devices.get(dev_index).unwrap().target_firmware.to_string(),
+                device_group: devices.get(dev_index).unwrap().device_group.to_string()
             };
             purge_device_by_index(dev_index);
             save_settings(device_to_save);
+            info!(mac = esp_id, alias = esp_alias, "Assigned alias to device.");
+            events::publish(events::Event::Assigned { mac: esp_id, field: "alias", value: esp_alias });
         }
     }
     HttpResponse::Ok().body(String::from("Assigned alias to device."))
 }
+// This function assigns the group in the esp-group header to the device in esp-device-id. Saves to configuration file.
+async fn assign_group(req: HttpRequest) -> impl Responder {
+    // Get the headers from the request. Admin credentials are checked by the `AdminAuth` middleware.
+    let headers: &HeaderMap = req.headers();
+    let header = |name: &str| headers.get(name).and_then(|h| h.to_str().ok()).map(str::trim).filter(|v| !v.is_empty());
+    let (esp_id, esp_group) = match (header("esp-device-id"), header("esp-group")) {
+        (Some(esp_id), Some(esp_group)) => (esp_id, esp_group),
+        _ => return HttpResponse::BadRequest().body("Missing esp-device-id or esp-group header.")
+    };
+    let mut devices = match load_deice_config() {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!(error = %e, "Error loading device configuration.");
+            return HttpResponse::InternalServerError().finish()
+        }
+    };
+    match devices.iter_mut().find(|device| device.device_id.eq_ignore_ascii_case(esp_id)) {
+        Some(device) => device.device_group = esp_group.to_string(),
+        _ => return HttpResponse::NotFound().body("Device not registered.")
+    }
+    if let Err(e) = try_save(devices) {
+        error!(error = %e, "Error saving device configuration.");
+        return HttpResponse::InternalServerError().finish()
+    }
+    info!(mac = esp_id, group = esp_group, "Assigned group to device.");
+    events::publish(events::Event::Assigned { mac: esp_id, field: "group", value: esp_group });
+    HttpResponse::Ok().body(String::from("Assigned group to device."))
+}
+// This function lists the registered devices as JSON.
+async fn list_devices() -> impl Responder {
+    match load_deice_config() {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(e) => {
+            error!(error = %e, "Error loading device configuration.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function lists the registered devices with their target, reported version, last check and update status.
+async fn list_fleet() -> impl Responder {
+    match fleet::list_fleet() {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(e) => {
+            error!(error = %e, "Error loading fleet.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function lists the rollouts of every target.
+async fn list_rollouts() -> impl Responder {
+    match rollout::list_rollouts() {
+        Ok(rollouts) => HttpResponse::Ok().json(rollouts),
+        Err(e) => {
+            error!(error = %e, "Error loading rollouts.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function queues the command in the esp-command header for the device in esp-device-id. It expires after
+// esp-command-expiry-secs seconds, or `commands.expiry_secs`.
+async fn send_command(req: HttpRequest) -> impl Responder {
+    let headers: &HeaderMap = req.headers();
+    let mac = match headers.get("esp-device-id").and_then(|h| h.to_str().ok()).and_then(find_device) {
+        Some(device) => device.device_id,
+        _ => return HttpResponse::NotFound().body("Missing esp-device-id header or device not registered.")
+    };
+    let command = match headers.get("esp-command").and_then(|h| h.to_str().ok()).and_then(commands::CommandKind::parse) {
+        Some(command) => command,
+        _ => return HttpResponse::BadRequest().body("esp-command must be reboot, factory_reset or force_update.")
+    };
+    let expiry = match headers.get("esp-command-expiry-secs").and_then(|h| h.to_str().ok()) {
+        Some(expiry) => match commands::parse_expiry(expiry) {
+            Ok(expiry) => Some(expiry),
+            _ => return HttpResponse::BadRequest()
+                .body(format!("esp-command-expiry-secs must lie between 1 and {}.", commands::MAX_EXPIRY_SECS))
+        },
+        _ => None
+    };
+    match commands::enqueue(&mac, command, expiry) {
+        Ok(queued) => {
+            info!(mac = %queued.mac, id = %queued.id, command = ?command, expires = %queued.expires, "Queued command.");
+            events::publish(events::Event::CommandQueued { mac: &queued.mac, id: &queued.id, command });
+            HttpResponse::Ok().json(queued)
+        }
+        Err(e) => {
+            error!(error = %e, "Error queueing command.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function cancels the unfinished command in the esp-command-id header.
+async fn cancel_command(req: HttpRequest) -> impl Responder {
+    let id = match req.headers().get("esp-command-id").and_then(|h| h.to_str().ok()) {
+        Some(id) => id.trim().to_string(),
+        _ => return HttpResponse::BadRequest().body("Missing esp-command-id header.")
+    };
+    match commands::cancel(&id) {
+        Ok(Some(command)) => {
+            info!(mac = %command.mac, id = %id, command = ?command.command, "Cancelled command.");
+            events::publish(events::Event::CommandFinished { mac: &command.mac, id: &id, command: command.command, state: command.state });
+            HttpResponse::Ok().json(command)
+        }
+        Ok(None) => HttpResponse::NotFound().body(String::from("No unfinished command with this id.")),
+        Err(e) => {
+            error!(error = %e, "Error saving commands.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function lists the queued and finished commands of every device, or only of the one in esp-device-id.
+async fn list_device_commands(req: HttpRequest) -> impl Responder {
+    let mac = req.headers().get("esp-device-id").and_then(|h| h.to_str().ok()).map(str::trim);
+    match commands::list_commands(mac) {
+        Ok(commands) => HttpResponse::Ok().json(commands),
+        Err(e) => {
+            error!(error = %e, "Error loading commands.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function lists every configuration layer with its revision.
+async fn list_configs() -> impl Responder {
+    match remoteconfig::load_configs() {
+        Ok(configs) => HttpResponse::Ok().json(configs),
+        Err(e) => {
+            error!(error = %e, "Error loading device_config.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function changes the configuration layer of a target, group or device, named in the esp-config-scope and
+// esp-config-name headers, with the JSON object in the body. Keys set to null are removed; with esp-config-replace the
+// body replaces the layer.
+async fn set_config(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    let headers: &HeaderMap = req.headers();
+    let scope = match headers.get("esp-config-scope").and_then(|h| h.to_str().ok()).and_then(remoteconfig::Scope::parse) {
+        Some(scope) => scope,
+        _ => return HttpResponse::BadRequest().body("esp-config-scope must be target, group or device.")
+    };
+    let name = match headers.get("esp-config-name").and_then(|h| h.to_str().ok()) {
+        Some(name) if !name.trim().is_empty() => name.trim().to_string(),
+        _ => return HttpResponse::BadRequest().body("Missing esp-config-name header.")
+    };
+    let changes = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(serde_json::Value::Object(changes)) => changes,
+        _ => return HttpResponse::BadRequest().body("The body must be a JSON object.")
+    };
+    let replace = headers.get("esp-config-replace").and_then(|h| h.to_str().ok()).map(|v| v == "true").unwrap_or(false);
+    match remoteconfig::update_layer(scope, &name, changes, replace) {
+        Ok(revision) => {
+            info!(scope = ?scope, name = %name, revision, "Changed device configuration.");
+            events::publish(events::Event::ConfigChanged { scope, name: &name, revision });
+            HttpResponse::Ok().body(format!("Saved revision {}.", revision))
+        }
+        Err(e) => {
+            warn!(scope = ?scope, name = %name, error = %e, "Error changing device configuration.");
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+    }
+}
+// This function sets the share of devices a target's firmware is offered to, and pauses or resumes its rollout.
+async fn set_rollout(req: HttpRequest) -> impl Responder {
+    let headers: &HeaderMap = req.headers();
+    let target = match headers.get("esp-target-firmware").and_then(|h| h.to_str().ok()) {
+        Some(target) if !target.is_empty() && !target.contains("..") => remove_whitespace(target),
+        _ => return HttpResponse::BadRequest().body("Missing or invalid esp-target-firmware header.")
+    };
+    let percent = match headers.get("esp-rollout-percent").and_then(|h| h.to_str().ok()) {
+        Some(percent) => match percent.parse::<u8>() {
+            Ok(percent) if percent <= 100 => percent,
+            _ => return HttpResponse::BadRequest().body("esp-rollout-percent must be between 0 and 100.")
+        },
+        _ => 100
+    };
+    let paused = headers.get("esp-rollout-paused").and_then(|h| h.to_str().ok()).map(|v| v == "true").unwrap_or(false);
+    match rollout::set_rollout(&target, percent, paused) {
+        Ok(rollout) => {
+            info!(target = %target, percent, paused, "Set rollout.");
+            events::publish(events::Event::RolloutChanged { target: &target, percent, paused });
+            HttpResponse::Ok().json(rollout)
+        }
+        Err(e) => {
+            error!(error = %e, "Error saving rollouts.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function stores an uploaded firmware binary and its compile time for a target.
+async fn upload_firmware(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    // Get the headers from the request. Admin credentials are checked by the `AdminAuth` middleware.
+    let headers: &HeaderMap = req.headers();
+    let target = match headers.get("esp-target-firmware").and_then(|h| h.to_str().ok()) {
+        Some(target) if !target.is_empty() && !target.contains("..") => remove_whitespace(target),
+        _ => return HttpResponse::BadRequest().body("Missing or invalid esp-target-firmware header.")
+    };
+    let compile_time = match headers.get("esp-compile-time").and_then(|h| h.to_str().ok()) {
+        Some(compile_time) => compile_time.to_string(),
+        _ => return HttpResponse::BadRequest().body("Missing esp-compile-time header.")
+    };
+    // Verify a signature supplied by CI or sign the binary before anything is written.
+    let supplied_signature = headers.get("esp-firmware-signature").and_then(|h| h.to_str().ok());
+    let signature = match firmware::signature_for_upload(&body, supplied_signature) {
+        Ok(signature) => signature,
+        Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let target_path = format!("{}{}", get_config_path(), target);
+    if let Err(e) = encryption::write_firmware(&format!("{}.ino.bin", target_path), &body) {
+        error!(target = %target, error = %e, "Error writing firmware.");
+        return HttpResponse::InternalServerError().finish()
+    }
+    if let Err(e) = firmware::save_signature(&target_path, signature.as_ref()) {
+        error!(target = %target, error = %e, "Error writing firmware signature.");
+        return HttpResponse::InternalServerError().finish()
+    }
+    if let Err(e) = std::fs::write(format!("{}.ct", target_path), compile_time) {
+        error!(target = %target, error = %e, "Error writing compile time.");
+        return HttpResponse::InternalServerError().finish()
+    }
+    info!(target = %target, bytes = body.len(), signed = signature.is_some(), "Stored firmware.");
+    events::publish(events::Event::FirmwareUploaded { target: &target, bytes: body.len(), signed: signature.is_some() });
+    HttpResponse::Ok().body(String::from("Uploaded firmware."))
+}
+// This function lists the devices that were seen sending their credentials over plain HTTP.
+async fn list_insecure_devices() -> impl Responder {
+    match policy::list_insecure_devices() {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(e) => {
+            error!(error = %e, "Error loading insecure_devices.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function reports the clients currently banned or with failed authentication attempts.
+async fn list_rate_limits() -> impl Responder {
+    HttpResponse::Ok().json(ratelimit::status())
+}
+// This function answers liveness probes. It does no work, a response means the process is serving requests.
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().content_type("text/plain").body("ok")
+}
+// This function answers readiness probes, 503 if the registry, the firmware store or the keys cannot be read.
+async fn readyz() -> impl Responder {
+    let readiness = health::readiness();
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        warn!(checks = ?readiness.checks, "Readiness check failed.");
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+// This function reports the build information of the server.
+async fn version() -> impl Responder {
+    HttpResponse::Ok().json(health::version())
+}
+// This function streams fleet events to the client as Server-Sent Events, one JSON object per event.
+async fn stream_events() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("cache-control", "no-cache")
+        .streaming(events::subscribe().map(Ok::<_, actix_web::Error>))
+}
+// This function lists the most recent webhook delivery attempts.
+async fn list_webhook_deliveries() -> impl Responder {
+    match webhooks::recent_deliveries(100) {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries),
+        Err(e) => {
+            error!(error = %e, "Error loading webhook_deliveries.log.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function pushes the current firmware of a device's target to it over espota, for devices running ArduinoOTA.
+// The push runs in the background; its progress is published as events and recorded in the update history.
+async fn push_firmware(req: HttpRequest) -> impl Responder {
+    let headers: &HeaderMap = req.headers();
+    let mac = match headers.get("esp-device-id").and_then(|h| h.to_str().ok()) {
+        Some(mac) if !mac.trim().is_empty() => mac.trim().to_string(),
+        _ => return HttpResponse::BadRequest().body("Missing esp-device-id header.")
+    };
+    let ip = match headers.get("esp-device-ip").and_then(|h| h.to_str().ok()).and_then(|ip| ip.trim().parse().ok()) {
+        Some(ip) => ip,
+        _ => return HttpResponse::BadRequest().body("Missing or invalid esp-device-ip header.")
+    };
+    let port = match headers.get("esp-ota-port").and_then(|h| h.to_str().ok()) {
+        Some(port) => match port.trim().parse() {
+            Ok(port) => Some(port),
+            _ => return HttpResponse::BadRequest().body("Invalid esp-ota-port header.")
+        },
+        _ => None
+    };
+    match espota::prepare(&mac, ip, port) {
+        Ok(push) => {
+            let response = HttpResponse::Accepted().json(&push);
+            std::thread::spawn(move || {
+                let _ = push.run();
+            });
+            response
+        }
+        Err(reason) => {
+            warn!(mac = %mac, reason, "Refusing to push firmware.");
+            let mut response = match reason {
+                "unknown_device" | "no_firmware" => HttpResponse::NotFound(),
+                "push_in_progress" => HttpResponse::Conflict(),
+                _ => HttpResponse::ServiceUnavailable(),
+            };
+            response.header("x-rota-reason", reason).body(reason)
+        }
+    }
+}
+// This function lists the most recent steps of updates sent to devices, of every device or only the one named in the
+// esp-device-id header.
+async fn list_update_history(req: HttpRequest) -> impl Responder {
+    let mac = req.headers().get("esp-device-id").and_then(|h| h.to_str().ok()).map(str::trim);
+    match history::recent_updates(mac, 100) {
+        Ok(updates) => HttpResponse::Ok().json(updates),
+        Err(e) => {
+            error!(error = %e, "Error loading update_history.log.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function lists the devices found advertising ArduinoOTA on the LAN.
+async fn list_discovered_devices() -> impl Responder {
+    match mdns::list_discovered() {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(e) => {
+            error!(error = %e, "Error loading discovered_devices.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function exports the server metrics in the Prometheus text format.
+async fn export_metrics() -> impl Responder {
+    match metrics::render() {
+        Ok(text) => HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(text),
+        Err(e) => {
+            error!(error = %e, "Error rendering metrics.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function lifts the ban on the client address given in the `esp-unban` header.
+async fn unban_client(req: HttpRequest) -> impl Responder {
+    match req.headers().get("esp-unban").and_then(|h| h.to_str().ok()) {
+        Some(client) if ratelimit::unban(client.trim()) => HttpResponse::Ok().body(String::from("Lifted ban.")),
+        Some(_) => HttpResponse::NotFound().body(String::from("Client is not banned.")),
+        _ => HttpResponse::BadRequest().body(String::from("Missing esp-unban header."))
+    }
+}
+// This function lists the device API keys without their hashes.
+async fn list_api_keys() -> impl Responder {
+    match keys::list_keys() {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(e) => {
+            error!(error = %e, "Error loading api_keys.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function creates a device API key. The plaintext key is only returned in this response.
+async fn create_api_key(req: HttpRequest) -> impl Responder {
+    // Get the headers from the request. Admin credentials are checked by the `AdminAuth` middleware.
+    let headers: &HeaderMap = req.headers();
+    let label = match headers.get("esp-key-label").and_then(|h| h.to_str().ok()) {
+        Some(label) => label,
+        _ => return HttpResponse::BadRequest().body("Missing esp-key-label header.")
+    };
+    let expires_in_days = match header_days(headers, "esp-key-expires-days") {
+        Ok(days) => days,
+        Err(_) => return HttpResponse::BadRequest().body(format!("esp-key-expires-days must lie between 1 and {}.", keys::MAX_DAYS))
+    };
+    match keys::create_key(label, expires_in_days) {
+        Ok(issued) => HttpResponse::Ok().json(issued),
+        Err(e) => {
+            error!(error = %e, "Error saving api_keys.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function rotates a device API key. The old key stays valid for the overlap period.
+async fn rotate_api_key(req: HttpRequest) -> impl Responder {
+    // Get the headers from the request. Admin credentials are checked by the `AdminAuth` middleware.
+    let headers: &HeaderMap = req.headers();
+    let id = match headers.get("esp-key-id").and_then(|h| h.to_str().ok()) {
+        Some(id) => id,
+        _ => return HttpResponse::BadRequest().body("Missing esp-key-id header.")
+    };
+    let overlap_days = match header_days(headers, "esp-overlap-days") {
+        Ok(days) => days.unwrap_or(keys::DEFAULT_ROTATION_OVERLAP_DAYS),
+        Err(_) => return HttpResponse::BadRequest().body(format!("esp-overlap-days must lie between 1 and {}.", keys::MAX_DAYS))
+    };
+    match keys::rotate_key(id, overlap_days) {
+        Ok(Some(issued)) => HttpResponse::Ok().json(issued),
+        Ok(None) => HttpResponse::NotFound().body("No active key with that id."),
+        Err(e) => {
+            error!(error = %e, "Error saving api_keys.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function revokes a device API key immediately.
+async fn revoke_api_key(req: HttpRequest) -> impl Responder {
+    // Get the headers from the request. Admin credentials are checked by the `AdminAuth` middleware.
+    let headers: &HeaderMap = req.headers();
+    let id = match headers.get("esp-key-id").and_then(|h| h.to_str().ok()) {
+        Some(id) => id,
+        _ => return HttpResponse::BadRequest().body("Missing esp-key-id header.")
+    };
+    match keys::revoke_key(id) {
+        Ok(true) => HttpResponse::Ok().body(String::from("Revoked key.")),
+        Ok(false) => HttpResponse::NotFound().body("No key with that id."),
+        Err(e) => {
+            error!(error = %e, "Error saving api_keys.toml.");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+// This function parses an optional day count header, see `keys::parse_days`.
+fn header_days(headers: &HeaderMap, name: &str) -> Result<Option<i64>, Box<dyn Error>> {
+    match headers.get(name) {
+        Some(val) => Ok(Some(keys::parse_days(val.to_str()?)?)),
+        _ => Ok(None)
+    }
+}
 // This function removes a device from the configuration file by index.
 fn purge_device_by_index(index: usize) {
     // Load config into memory in the form of Vec<EspDevice>
     let mut configuration: Vec<EspDevice> = load_deice_config().unwrap();
-    configuration.remove(index);
+    let removed = configuration.remove(index);
+    debug!(mac = %removed.device_id, "Purged device from configuration.");
     // Save the device configuration
     match try_save(configuration) {
         Ok(_) => {},
@@ -256,7 +843,7 @@ fn save_settings(to_save: EspDevice) {
     if !exists {
         configuration.push(to_save);
     } else {
-        println!("Device already in configuration file!");
+        warn!(mac = %to_save.device_id, "Device already in configuration file.");
     }
 
     // Save the device configuration
@@ -275,8 +862,9 @@ fn try_save(configuration: std::vec::Vec<EspDevice>) -> std::io::Result<()>{
     // Create the config file. Destroys the old copy.
     let mut save_file = File::create(path)?;
     // Write bundled device values into the file...
+    debug!(devices = configuration.len(), "Saving device configuration.");
     let devices = bundle_devices(configuration);
-    save_file.write_all(format!("device_id = '{}'\ndevice_alias = '{}'\ntarget_firmware = '{}'", devices.device_id, devices.device_alias, devices.target_firmware).into_bytes().as_ref())?;
+    save_file.write_all(format!("device_id = '{}'\ndevice_alias = '{}'\ntarget_firmware = '{}'\ndevice_group = '{}'", devices.device_id, devices.device_alias, devices.target_firmware, devices.device_group).into_bytes().as_ref())?;
     save_file.sync_data()?;
     Ok(())
 }
@@ -285,23 +873,27 @@ fn bundle_devices(devices: std::vec::Vec<EspDevice>) -> EspDevice {
     let mut device_id = String::from("");
     let mut device_alias = String::from("");
     let mut target_firmware = String::from("");
+    let mut device_group = String::from("");
 
     // Bundle all host fields into one string using the pipe character as the delimiter.
     for device in devices.iter() {
-        device_id.push_str(format!("{}{}",device.clone().device_id.as_str(), "|").as_str());
-        device_alias.push_str(format!("{}{}",device.clone().device_alias.as_str(), "|").as_str());
-        target_firmware.push_str(format!("{}{}",device.clone().target_firmware.as_str(), "|").as_str());
+        device_id.push_str(format!("{}{}",device.device_id.as_str(), "|").as_str());
+        device_alias.push_str(format!("{}{}",device.device_alias.as_str(), "|").as_str());
+        target_firmware.push_str(format!("{}{}",device.target_firmware.as_str(), "|").as_str());
+        device_group.push_str(format!("{}{}",device.device_group.as_str(), "|").as_str());
     }
 
-    // Remove trailing pipe from fields
-    device_id = device_id[0..device_id.len() - 1].parse().unwrap();
-    device_alias = device_alias[0..device_alias.len() - 1].parse().unwrap();
-    target_firmware = target_firmware[0..target_firmware.len() - 1].parse().unwrap();
+    // Remove trailing pipe from fields. There is none if the last device was just purged.
+    device_id.pop();
+    device_alias.pop();
+    target_firmware.pop();
+    device_group.pop();
 
     EspDevice{
         device_id,
         device_alias,
         target_firmware,
+        device_group,
     }
 }
 // This function loads settings config file into a `Vec<espDevices>`
@@ -315,94 +907,194 @@ fn load_deice_config() -> Result<std::vec::Vec<EspDevice>, Box<dyn Error>> {
     path.push(Path::new(".config/rota_example/devices.toml"));
     match settings.merge(config::File::from(path)) {
         Ok(_) => {},
-        Err(e) => println!("Error merging configuration file {}", e)
+        Err(e) => error!(error = %e, "Error merging device configuration file.")
     }
     let ids: std::vec::Vec<String> = to_string_vec(settings.get::<String>("device_id")?.split("|").collect());
     let aliases: std::vec::Vec<String> = to_string_vec(settings.get::<String>("device_alias")?.split("|").collect());
     let firmwares: std::vec::Vec<String> = to_string_vec(settings.get::<String>("target_firmware")?.split("|").collect());
+    // Groups were added later, older files do not have them.
+    let groups: std::vec::Vec<String> = match settings.get::<String>("device_group") {
+        Ok(groups) => to_string_vec(groups.split("|").collect()),
+        Err(_) => vec!()
+    };
 
     let mut r_devices: std::vec::Vec<EspDevice> = vec!();
     for i in 0..ids.len() {
+        // An empty id is left behind when every device was purged.
+        if ids[i].is_empty() {
+            continue;
+        }
         r_devices.push(EspDevice {
-            device_id: String::from(ids[i].clone()),
-            device_alias: String::from(aliases[i].clone()),
-            target_firmware: String::from(firmwares[i].clone()),
+            device_id: ids[i].clone(),
+            device_alias: aliases[i].clone(),
+            target_firmware: firmwares[i].clone(),
+            device_group: groups.get(i).cloned().unwrap_or_else(unassigned),
         });
     }
 
+    debug!(devices = r_devices.len(), "Loaded device configuration.");
     Ok(r_devices)
 }
+// This function looks up a registered device by MAC address.
+fn find_device(mac: &str) -> Option<EspDevice> {
+    load_deice_config().ok()?.into_iter().find(|device| device.device_id.eq_ignore_ascii_case(mac))
+}
+// This function admits a request from a device: it must come from an ESP device that is not rate limited, authenticate
+// and satisfy the HTTPS policy. Returns the client IP and uppercase MAC, or the response refusing the request.
+fn admit_device(req: &HttpRequest) -> Result<(String, String), HttpResponse> {
+    let headers = req.headers();
+    if !check_device_is_allowed(headers) {
+        // Device is not allowed, send 403 Forbidden.
+        warn!(decision = "reject", reason = "unknown_device_type", "Rejected a request without ESP headers.");
+        securitylog::record(403, "unknown_device_type", &ClientInfo::of(req).ip_string(), None, req.path());
+        return Err(HttpResponse::Forbidden().finish())
+    }
+    let client_mac = extract_mac_addr_string(headers).to_uppercase();
+    logging::record_mac(&client_mac);
+    let client_ip = admit(req, &client_mac, headers.contains_key("x-esp32-sta-mac"), || validate_api_key(headers))?;
+    Ok((client_ip, client_mac))
+}
+// This function admits a request from the device with the MAC `mac`, however it names itself: it must not be rate
+// limited, authenticate and satisfy the HTTPS policy. `check_key` validates the key it presented, for devices that
+// neither present a client certificate nor sign their requests. Returns the client IP, or the response refusing the
+// request.
+fn admit(req: &HttpRequest, mac: &str, esp32: bool, check_key: impl FnOnce() -> Result<bool, Box<dyn Error>>)
+    -> Result<String, HttpResponse> {
+    // Refuse banned clients and clients sending too many requests, send 429 Too Many Requests.
+    let client_ip = ClientInfo::of(req).ip_string();
+    let clients = [(ClientKind::Ip, client_ip.as_str()), (ClientKind::Mac, mac)];
+    if let Err(refusal) = ratelimit::check(&clients) {
+        securitylog::record(429, refusal.reason(), &client_ip, Some(mac), req.path());
+        return Err(refusal.response())
+    }
+    match authenticate_device(req, mac, esp32, check_key) {
+        Ok(()) => {}
+        // The credentials could not be read, a server fault rather than a failed attempt.
+        Err(CREDENTIALS_UNAVAILABLE) => return Err(HttpResponse::InternalServerError().finish()),
+        Err(reason) => {
+            // Signature or API key not accepted, send 401 Unauthorized with the reason code.
+            warn!(decision = "reject", reason, mac, "Device failed to authenticate.");
+            securitylog::record(401, reason, &client_ip, Some(mac), req.path());
+            ratelimit::record_failure(&clients);
+            return Err(HttpResponse::Unauthorized().header("x-rota-reason", reason).body(reason))
+        }
+    }
+    ratelimit::record_success(&clients);
+    debug!("Device authenticated.");
+    // Warn about or refuse a device sending its API key over an unencrypted HTTP connection, depending on the HTTPS policy.
+    if let Some(refused) = policy::enforce(req, mac) {
+        return Err(refused)
+    }
+    Ok(client_ip)
+}
 // This function converts a vec<str> to a vec<String>
 fn to_string_vec(as_an_str: std::vec::Vec<&str>) -> std::vec::Vec<String>  {
-    as_an_str.into_iter().map(|elem| String::from(elem)).collect()
+    as_an_str.into_iter().map(String::from).collect()
 }
-// This function parses the `x-forwarded-proto` header to determine http protocol of client. Returns true for HTTPS, false for HTTP.
-fn client_using_https(headers: &HeaderMap) -> bool {
-    match headers.get("x-forwarded-proto") {
-        Some(val) => match val.to_str().unwrap() {
-            "https" => true,
-            _ => false
-        }
-        _ => false // Assume worst case if we cannot tell.
+// This function loads the `targets` file, which maps each MAC address to the firmware it is served.
+fn load_targets() -> io::Result<Vec<(String, String)>> {
+    let file = std::fs::read_to_string(std::path::Path::new(format!("{}{}", get_config_path(), "targets").as_str()))?;
+    Ok(file.lines().filter_map(|line| line.split_once(','))
+        .map(|(mac, target)| (mac.to_string(), remove_whitespace(target)))
+        .collect())
+}
+// This function sets the firmware a device is served in the `targets` file. The file is kept by hand too, so every
+// other line, comments included, is left as it is.
+fn save_target(mac: &str, target: &str) -> io::Result<()> {
+    let path = format!("{}{}", get_config_path(), "targets");
+    let file = match std::fs::read_to_string(&path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let entry = format!("{}, {}", mac, target);
+    let mut lines: Vec<&str> = file.lines().collect();
+    let existing = lines.iter().position(|line| line.split_once(',').map(|(m, _)| m.eq_ignore_ascii_case(mac)).unwrap_or(false));
+    match existing {
+        Some(index) => lines[index] = &entry,
+        _ => lines.push(&entry),
     }
+    let file: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    store::write_atomically(&path, &file)
 }
 // This function constructs a path to the version of the firmware the device is set to download in `espota/targets`.
-fn construct_target_firmware_path_string(headers: &HeaderMap) -> String {
+// Returns `None` if the device is not in the targets file.
+fn construct_target_firmware_path_string(headers: &HeaderMap) -> io::Result<Option<String>> {
     // Extract mac address string from request.
     let mac_addr = extract_mac_addr_string(headers);
     // Open up the target firmware file
-    if let Ok(f) = std::fs::read_to_string(std::path::Path::new(format!("{}{}", get_config_path(), "targets").as_str())) {
-        let lines = f.lines();
-        for line in lines {
-            let split_line: Vec<&str> = line.split(",").collect();
-            if split_line[0] == mac_addr.as_str() {
-                //println!("{}::{}", &split_line[0], &split_line[1]);
-                return format!("{}{}", get_config_path(), remove_whitespace(split_line[1]).as_str());
-            }
+    Ok(load_targets()?.into_iter().find(|(mac, _)| *mac == mac_addr).map(|(_, target)| format!("{}{}", get_config_path(), target)))
+}
+// What an admitted device runs and is served: the version it reports, its target and the latest firmware of that target.
+struct DeviceFirmware {
+    running: DateTime<Utc>,
+    target_path: String,
+    target: String,
+    latest: DateTime<Utc>,
+}
+// This function works out the firmware of an admitted device. Returns the response to send instead if its version header
+// is malformed, it has no target or the targets file or the firmware cannot be read.
+fn device_firmware(req: &HttpRequest) -> Result<DeviceFirmware, HttpResponse> {
+    let headers = req.headers();
+    let running = match extract_firmware_string(headers).as_deref().and_then(parse_version_str) {
+        Some(running) => running,
+        _ => {
+            warn!(decision = "reject", reason = "invalid_version", "Device sent a malformed version header.");
+            return Err(HttpResponse::BadRequest().header("x-rota-reason", "invalid_version").body("invalid_version"))
         }
-        panic!("Path to target binary could not be created.");
-    } else {
-        panic!("Error reading targets file.");
-    }
+    };
+    let target_path = match construct_target_firmware_path_string(headers) {
+        Ok(Some(target_path)) => target_path,
+        Ok(None) => {
+            warn!(decision = "reject", reason = "no_target", "Device has no target in the targets file.");
+            return Err(HttpResponse::NotFound().header("x-rota-reason", "no_target").body("no_target"))
+        }
+        Err(e) => {
+            error!(error = %e, "Error reading targets file.");
+            return Err(HttpResponse::InternalServerError().finish())
+        }
+    };
+    let latest = match read_compile_time(&target_path) {
+        Ok(latest) => latest,
+        Err(e) => {
+            error!(target = %target_path, error = e, "Error reading firmware compile time.");
+            return Err(HttpResponse::ServiceUnavailable().header("x-rota-reason", "no_firmware").body("no_firmware"))
+        }
+    };
+    let target = Path::new(&target_path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    Ok(DeviceFirmware { running, target_path, target, latest })
 }
 // This function removes whitespace from str.
 fn remove_whitespace(s: &str) -> String {
     s.chars().filter(|c| !c.is_whitespace()).collect()
 }
-// This function extracts the version header string.
-fn extract_firmware_string(headers: &HeaderMap) -> String {
-    match headers.get("x-esp8266-version") {
-        Some(val) => String::from(val.to_str().unwrap().clone()),
-        _ => {
-            // If no ESP8266 headers are detected, then try for ESP32 headers.
-            match headers.get("x-esp32-version") {
-                Some(val) => String::from(val.to_str().unwrap().clone()),
-                _ => panic!("Device not an ESP8266/32")
-            }
-        }
-    }
+// This function extracts the version header string, without a legacy `?key` suffix. Returns `None` if there is none.
+fn extract_firmware_string(headers: &HeaderMap) -> Option<String> {
+    // If no ESP8266 headers are detected, then try for ESP32 headers.
+    let version = headers.get("x-esp8266-version").or_else(|| headers.get("x-esp32-version"))?.to_str().ok()?;
+    Some(String::from(version.split('?').next().unwrap_or(version)))
 }
-// This function extracts the mac address header string.
+// This function extracts the mac address header string. It is empty if the device sent none or an unreadable one.
 fn extract_mac_addr_string(headers: &HeaderMap) -> String {
-    match headers.get("x-esp8266-sta-mac") {
-        Some(val) => String::from(val.to_str().unwrap().clone()),
-        _ => {
-            // If no ESP8266 headers are detected, then try for ESP32 headers.
-            match headers.get("x-esp32-sta-mac") {
-                Some(val) => String::from(val.to_str().unwrap().clone()),
-                _ => panic!("Device not an ESP8266/32")
-            }
-        }
-    }
+    // If no ESP8266 headers are detected, then try for ESP32 headers.
+    let mac = headers.get("x-esp8266-sta-mac").or_else(|| headers.get("x-esp32-sta-mac"));
+    String::from(mac.and_then(|val| val.to_str().ok()).unwrap_or_default())
 }
-// This function retrieves the latest date for the firmware the device is set to download in `espota/targets` as a `chrono::DateTime<Utc>`.
-fn get_latest_firmware_date(headers: &HeaderMap) -> DateTime<Utc> {
-    if let Ok(file) = std::fs::read_to_string(std::path::Path::new(format!("{}.ct", construct_target_firmware_path_string(headers)).as_str())) {
+// This function retrieves the compile time of a target's firmware, for listings that must not panic.
+fn latest_firmware_date(target: &str) -> Result<DateTime<Utc>, &'static str> {
+    read_compile_time(&format!("{}{}", get_config_path(), target))
+}
+// This function reads and parses the `.ct` file holding the compile time stored with a firmware binary.
+fn read_compile_time(target_path: &str) -> Result<DateTime<Utc>, &'static str> {
+    if let Ok(file) = std::fs::read_to_string(std::path::Path::new(format!("{}.ct", target_path).as_str())) {
         let lines: Vec<&str> = file.lines().collect();
         let line: String = lines.into_iter().map(String::from).collect();
+        if line.len() < 27 || !line.is_ascii() {
+            return Err("Error parsing stored compile time.")
+        }
         let year: i32 = match line[8..12].to_string().parse() {
             Ok(res) => res,
-            _ => panic!("Error parsing stored year.")
+            _ => return Err("Error parsing stored year.")
         };
         let month: u32 = match &line[1..4] {
             "Jan" => 1,
@@ -417,61 +1109,106 @@ fn get_latest_firmware_date(headers: &HeaderMap) -> DateTime<Utc> {
             "Oct" => 10,
             "Nov" => 11,
             "Dec" => 12,
-            _ => {panic!("Error parsing stored month.")}
+            _ => return Err("Error parsing stored month.")
         };
         let day: u32 = match &line[5..6] {
             " " => match line[6..7].parse() {
                 Ok(res) => res,
-                _ => panic!("Error parsing stored day.")
+                _ => return Err("Error parsing stored day.")
             }
             _ => match line[5..7].parse() {
                 Ok(res) => res,
-                _ => panic!("Error parsing stored day.")
+                _ => return Err("Error parsing stored day.")
             }
         };
         let hour: u32 = match line[19..21].parse() {
             Ok(res) => res,
-            _ => panic!("Error parsing stored hour.")
+            _ => return Err("Error parsing stored hour.")
         };
         let minute: u32 = match line[22..24].parse() {
             Ok(res) => res,
-            _ => panic!("Error parsing stored minute.")
+            _ => return Err("Error parsing stored minute.")
         };
         let second: u32 = match line[25..27].parse() {
             Ok(res) => res,
-            _ => panic!("Error parsing stored second.")
+            _ => return Err("Error parsing stored second.")
         };
-        Utc.ymd(year, month, day).and_hms(hour, minute, second)
+        Utc.ymd_opt(year, month, day).and_hms_opt(hour, minute, second).single().ok_or("Error parsing stored compile time.")
     } else {
-        panic!("Error reading compile time");
+        Err("Error reading compile time")
+    }
+}
+// The reason `authenticate_device` gives when the device API keys or secrets cannot be read.
+const CREDENTIALS_UNAVAILABLE: &str = "credentials_unavailable";
+// This function authenticates the device with the MAC `mac` by its TLS client certificate, its request signature or the
+// key `check_key` validates, in that order of preference. Returns a reason code on failure.
+fn authenticate_device(req: &HttpRequest, mac: &str, esp32: bool, check_key: impl FnOnce() -> Result<bool, Box<dyn Error>>)
+    -> Result<(), &'static str> {
+    let headers = req.headers();
+    // A device presenting a client certificate is identified by it alone, the certificate must name the device's MAC.
+    if let Some(cert) = req.extensions().get::<tls::TlsConnection>().and_then(|c| c.peer_certificate.clone()) {
+        return if tls::certificate_matches_mac(&cert, mac) {
+            Ok(())
+        } else {
+            Err("certificate_mac_mismatch")
+        }
+    }
+    if SETTINGS.tls.require_esp32_client_cert && esp32 {
+        return Err("client_certificate_required")
+    }
+    if signing::is_signed(headers) {
+        return signing::verify_request(headers, mac)
+    }
+    if SETTINGS.request_signing.required {
+        return Err("signature_required")
+    }
+    match check_key() {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("invalid_api_key"),
+        Err(e) => {
+            error!(error = %e, "Error reading device credentials.");
+            Err(CREDENTIALS_UNAVAILABLE)
+        }
     }
 }
 // This function validates the clients api key.
-fn validate_api_key(headers: &HeaderMap) -> bool {
-    //let api_key = firmware_version_str.split("?").collect::<Vec<&str>>()[1];
-    let req_string = extract_firmware_string(headers);
-    let fields: Vec<&str> = req_string.split("?").collect();
-    let validating_key = fields[1];
-    if let Ok(file) = std::fs::read_to_string(std::path::Path::new(format!("{}{}", get_config_path().as_str(), "api_keys").as_str())) {
-        let mut lines = file.lines();
-        lines.any(|elem| elem == validating_key)
+fn validate_api_key(headers: &HeaderMap) -> Result<bool, Box<dyn Error>> {
+    let validating_key = match credentials::extract_api_key(headers) {
+        Some(key) => key,
+        _ => return Ok(false)
+    };
+    let validating_key = validating_key.as_str();
+    // Devices that were issued their own secret may only authenticate with it, never with a shared key.
+    if let Some(valid) = credentials::verify_device_secret(&extract_mac_addr_string(headers), validating_key)? {
+        return Ok(valid)
+    }
+    keys::validate_key(validating_key)
+}
+// This function formats a firmware date the way it is labelled in metrics.
+fn version_label(version: &DateTime<Utc>) -> String {
+    version.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+// This function names the kind of device a request came from, for logs.
+fn device_type(headers: &HeaderMap) -> &'static str {
+    if headers.contains_key("x-esp8266-sta-mac") {
+        "esp8266"
+    } else if headers.contains_key("x-esp32-sta-mac") {
+        "esp32"
     } else {
-        panic!("Error finding api_keys");
+        "unknown"
     }
 }
 // This function checks to see if the device is an ESP8266 or an ESP32.
 fn check_device_is_allowed(headers: &HeaderMap) -> bool {
-    match headers.get("x-esp8266-sta-mac") {
-        Some(_mac) => true,
-        _ => match headers.get("x-esp32-sta-mac") {
-            Some(_mac) => true,
-            _ => false,
-        }
-    }
+    !extract_mac_addr_string(headers).is_empty()
 }
-// This function extracts the currently running version from headers as a `chrono::DateTime<Utc>`.
-fn extract_version_from_version_str(req_string: &str) -> DateTime<Utc> {
-    let year: i32 = req_string[7..11].parse().clone().unwrap();
+// This function parses a version string in the `__DATE__ __TIME__` format devices send, e.g. `Oct 18 2026 12:00:00`.
+// Returns `None` if it is malformed.
+fn parse_version_str(req_string: &str) -> Option<DateTime<Utc>> {
+    if req_string.len() < 20 || !req_string.is_ascii() {
+        return None
+    }
+    let year: i32 = req_string[7..11].parse().ok()?;
     let month: u32 = match &req_string[0..3] {
         "Jan" => 1,
         "Feb" => 2,
@@ -485,30 +1222,157 @@ fn extract_version_from_version_str(req_string: &str) -> DateTime<Utc> {
         "Oct" => 10,
         "Nov" => 11,
         "Dec" => 12,
-        _ => {panic!("Error Parsing Month")}
+        _ => return None
     };
     let day: u32 = match &req_string[4..5] {
-        " " => req_string[5..6].parse().clone().unwrap(),
-        _ => req_string[4..6].parse().clone().unwrap()
+        " " => req_string[5..6].parse().ok()?,
+        _ => req_string[4..6].parse().ok()?
     };
-    let hour: u32 = req_string[12..14].parse().unwrap();
-    let minute: u32 = req_string[15..17].parse().unwrap();
-    let second: u32 = req_string[18..20].parse().unwrap();
-    Utc.ymd(year, month, day).and_hms(hour, minute, second)
+    let hour: u32 = req_string[12..14].parse().ok()?;
+    let minute: u32 = req_string[15..17].parse().ok()?;
+    let second: u32 = req_string[18..20].parse().ok()?;
+    Utc.ymd_opt(year, month, day).and_hms_opt(hour, minute, second).single()
 }
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
-    // Values used to set the listening address and port of the Actix-Web Server
-    let port: String = String::from("80");
-    let addr: String = String::from("localhost");
-    println!("Actix-web listening on {}:{}", addr, port);
-    let server = HttpServer::new(||
-        App::new()
-            .route("/ota", web::get().to(ota))
-            .route("/checkforupdate", web::get().to(check_for_firmware_update))
-            .route("/register", web::post().to(register_device))
-            .route("/assignfirmware", web::post().to(assign_firmware))
-            .route("/assignalias", web::post().to(assign_alias))
-    );
-    server.bind(format!("{}:{}", addr, port).as_str())?.run().await
-}
\ No newline at end of file
+    // Keep the guard alive so buffered log lines are flushed to the log file on exit.
+    let _log_guard = logging::init();
+    // Run a command line subcommand instead of the server if one was given.
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(code) = cli::run(&args) {
+        std::process::exit(code);
+    }
+    // Load the firmware signing key up front, so a broken key stops the server before it accepts requests.
+    if let Some(public_key) = firmware::public_key() {
+        info!(public_key = %public_key, "Signing firmware with Ed25519.");
+    }
+    encryption::load_keys();
+    metrics::init();
+    webhooks::start();
+    mqtt::start();
+    mdns::start();
+    // Values used to set the listening address and ports of the Actix-Web Server
+    let addr: &str = SETTINGS.listen_address.as_str();
+    let mut server = actix_server::Server::build();
+    // Plain HTTP can be served next to HTTPS for legacy ESP8266 boards that cannot do TLS.
+    if SETTINGS.serve_http {
+        info!("Actix-web listening on http://{}:{}", addr, SETTINGS.http_port);
+        server = server.bind("rota-http", format!("{}:{}", addr, SETTINGS.http_port), ||
+            HttpService::build()
+                .finish(map_config(App::new().wrap(logging::RequestTracing).configure(routes), |_| AppConfig::default()))
+                .tcp()
+        )?;
+    }
+    // The HTTPS listener is assembled by hand rather than with `HttpServer::bind_rustls`, so the client certificate
+    // of each connection can be attached to its requests for mutual TLS.
+    if SETTINGS.tls.enabled {
+        info!("Actix-web listening on https://{}:{}", addr, SETTINGS.tls.port);
+        let config = tls::server_config()?;
+        server = server.bind("rota-https", format!("{}:{}", addr, SETTINGS.tls.port), move ||
+            HttpService::build()
+                .on_connect(tls::TlsConnection::from_stream)
+                .finish(map_config(App::new().wrap(logging::RequestTracing).configure(routes), |_| AppConfig::default()))
+                .rustls(config.clone())
+        )?;
+    }
+    server.run().await
+}
+// This function registers every route of the server.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/ota", web::get().to(ota))
+        .route("/checkforupdate", web::get().to(check_for_firmware_update))
+        .route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz))
+        .route("/version", web::get().to(version))
+        .route("/config", web::get().to(get_config))
+        .route("/commands", web::get().to(get_commands))
+        .route("/ackcommand", web::post().to(ack_command))
+        .service(web::resource("/register")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(register_device)))
+        .service(web::resource("/assignfirmware")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(assign_firmware)))
+        .service(web::resource("/assignalias")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(assign_alias)))
+        .service(web::resource("/assigngroup")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(assign_group)))
+        .service(web::resource("/devices")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_devices)))
+        .service(web::resource("/insecuredevices")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_insecure_devices)))
+        .service(web::resource("/fleet")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_fleet)))
+        .service(web::resource("/rollouts")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_rollouts)))
+        .service(web::resource("/sendcommand")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(send_command)))
+        .service(web::resource("/cancelcommand")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(cancel_command)))
+        .service(web::resource("/devicecommands")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_device_commands)))
+        .service(web::resource("/configs")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_configs)))
+        .service(web::resource("/setconfig")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(set_config)))
+        .service(web::resource("/rollout")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(set_rollout)))
+        .service(web::resource("/uploadfirmware")
+            .wrap(AdminAuth::require(AdminScope::FirmwarePublisher))
+            .data(web::PayloadConfig::new(16 * 1024 * 1024))
+            .route(web::post().to(upload_firmware)))
+        .service(web::resource("/api/v1/events")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(stream_events)))
+        .service(web::resource("/push")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(push_firmware)))
+        .service(web::resource("/updatehistory")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_update_history)))
+        .service(web::resource("/discovereddevices")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_discovered_devices)))
+        .service(web::resource("/webhookdeliveries")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_webhook_deliveries)))
+        .service(web::resource("/metrics")
+            .wrap(Condition::new(SETTINGS.metrics.require_token, AdminAuth::require(AdminScope::ReadOnly)))
+            .route(web::get().to(export_metrics)))
+        .service(web::resource("/ratelimits")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_rate_limits)))
+        .service(web::resource("/unban")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(unban_client)))
+        .service(web::resource("/keys")
+            .wrap(AdminAuth::require(AdminScope::ReadOnly))
+            .route(web::get().to(list_api_keys)))
+        .service(web::resource("/createkey")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(create_api_key)))
+        .service(web::resource("/rotatekey")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(rotate_api_key)))
+        .service(web::resource("/revokekey")
+            .wrap(AdminAuth::require(AdminScope::FleetOperator))
+            .route(web::post().to(revoke_api_key)));
+    // The hawkBit DDI API, for update clients that speak it instead of rota's own routes.
+    ddi::routes(cfg);
+    // The dashboard is static, it signs in with an admin token and calls the admin routes above.
+    if !SETTINGS.dashboard.directory.is_empty() {
+        cfg.service(actix_files::Files::new("/dashboard", &SETTINGS.dashboard.directory).index_file("index.html"));
+    }
+}
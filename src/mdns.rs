@@ -0,0 +1,167 @@
+use chrono::{DateTime, Utc};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::error::Error;
+use std::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+use crate::fleet;
+use crate::settings::SETTINGS;
+use crate::store;
+
+// The service rota is advertised as, and the one ArduinoOTA devices advertise themselves as.
+const ROTA_SERVICE: &str = "_rota._tcp.local.";
+const HTTP_SERVICE: &str = "_http._tcp.local.";
+const ARDUINO_SERVICE: &str = "_arduino._tcp.local.";
+// Version of the API in the TXT records, the `/api/<version>` routes are served under.
+const API_VERSION: &str = "v1";
+
+lazy_static! {
+    // The responder. `None` unless mDNS is enabled.
+    static ref DAEMON: Mutex<Option<ServiceDaemon>> = Mutex::new(None);
+    // Serializes read-modify-write cycles on the discovered device list.
+    static ref DISCOVERED_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// A device found advertising ArduinoOTA on the LAN, as stored in `discovered_devices.toml`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DiscoveredDevice {
+    pub name: String,
+    pub hostname: String,
+    pub addresses: Vec<String>,
+    // The port ArduinoOTA listens for espota invitations on.
+    pub port: u16,
+    pub board: Option<String>,
+    pub auth_upload: bool,
+    // The registered device last seen at one of the addresses, if any.
+    pub mac: Option<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct DiscoveredStore {
+    devices: Vec<DiscoveredDevice>,
+}
+
+// The discovered device list in the configuration directory.
+const DISCOVERED: &str = "discovered_devices.toml";
+
+// This function loads the devices found so far.
+pub fn list_discovered() -> Result<Vec<DiscoveredDevice>, Box<dyn Error>> {
+    let _guard = DISCOVERED_LOCK.lock().unwrap();
+    load_discovered()
+}
+
+fn load_discovered() -> Result<Vec<DiscoveredDevice>, Box<dyn Error>> {
+    Ok(store::load::<DiscoveredStore>(DISCOVERED)?.devices)
+}
+
+// This function returns the TXT records rota is advertised with: where devices check for and download firmware,
+// whether the port speaks TLS and the API version.
+fn txt_records(tls: bool) -> Vec<(&'static str, String)> {
+    vec!(
+        ("path", String::from("/ota")),
+        ("check", String::from("/checkforupdate")),
+        ("tls", tls.to_string()),
+        ("api", String::from(API_VERSION)),
+        ("version", String::from(env!("CARGO_PKG_VERSION"))),
+    )
+}
+
+// This function advertises one service. Addresses follow the interfaces of the host unless the server listens on a
+// single address.
+fn advertise(daemon: &ServiceDaemon, service: &str, port: u16, tls: bool) -> Result<(), Box<dyn Error>> {
+    let mdns = &SETTINGS.mdns;
+    let address = match SETTINGS.listen_address.parse::<std::net::IpAddr>() {
+        Ok(address) if !address.is_unspecified() => address.to_string(),
+        _ => String::new(),
+    };
+    let hostname = format!("{}.local.", mdns.hostname);
+    let records = txt_records(tls);
+    let mut info = ServiceInfo::new(service, &mdns.instance_name, &hostname, address.as_str(), port, records.as_slice())?;
+    if address.is_empty() {
+        info = info.enable_addr_auto();
+    }
+    daemon.register(info)?;
+    info!(service, instance = %mdns.instance_name, port, tls, "Advertising over mDNS.");
+    Ok(())
+}
+
+// This function records a device found advertising ArduinoOTA, linking it to the registered device last seen at one of
+// its addresses.
+fn record_discovered(info: &ServiceInfo) -> Result<(), Box<dyn Error>> {
+    let mut addresses: Vec<String> = info.get_addresses().iter().map(|a| a.to_string()).collect();
+    addresses.sort();
+    let mac = fleet::list_fleet()?.into_iter()
+        .find(|device| device.last_ip.as_ref().map(|ip| addresses.contains(ip)).unwrap_or(false))
+        .map(|device| device.mac);
+    let now = Utc::now();
+    let found = DiscoveredDevice {
+        name: info.get_fullname().to_string(),
+        hostname: info.get_hostname().to_string(),
+        addresses,
+        port: info.get_port(),
+        board: info.get_property_val_str("board").map(String::from),
+        auth_upload: info.get_property_val_str("auth_upload") == Some("yes"),
+        mac,
+        first_seen: now,
+        last_seen: now,
+    };
+    info!(name = %found.name, addresses = ?found.addresses, mac = ?found.mac, "Discovered ArduinoOTA device.");
+    let _guard = DISCOVERED_LOCK.lock().unwrap();
+    let mut devices = load_discovered()?;
+    match devices.iter_mut().find(|d| d.name == found.name) {
+        Some(device) => *device = DiscoveredDevice { first_seen: device.first_seen, ..found },
+        _ => devices.push(found),
+    }
+    store::save(DISCOVERED, &DiscoveredStore { devices })
+}
+
+// This function browses for ArduinoOTA devices from its own thread.
+fn discover(daemon: &ServiceDaemon) -> Result<(), Box<dyn Error>> {
+    let receiver = daemon.browse(ARDUINO_SERVICE)?;
+    std::thread::spawn(move || {
+        while let Ok(event) = receiver.recv() {
+            match event {
+                ServiceEvent::ServiceResolved(info) => if let Err(e) = record_discovered(&info) {
+                    error!(error = %e, "Error saving discovered_devices.toml.");
+                },
+                ServiceEvent::ServiceRemoved(_, name) => debug!(name = %name, "ArduinoOTA device went away."),
+                _ => {}
+            }
+        }
+    });
+    info!(service = ARDUINO_SERVICE, "Discovering devices over mDNS.");
+    Ok(())
+}
+
+// This function advertises rota over mDNS if it is enabled, as `_rota._tcp` on the HTTPS port, or the HTTP port
+// without TLS, and as `_http._tcp` on the HTTP port, and starts discovering ArduinoOTA devices if configured.
+pub fn start() {
+    let mdns = &SETTINGS.mdns;
+    if !mdns.enabled {
+        return
+    }
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            error!(error = %e, "Error starting mDNS responder.");
+            return
+        }
+    };
+    if SETTINGS.listen_address == "localhost" {
+        warn!("Advertising over mDNS while listening on localhost only, devices will not be able to connect.");
+    }
+    let rota = if SETTINGS.tls.enabled { (SETTINGS.tls.port, true) } else { (SETTINGS.http_port, false) };
+    let mut result = advertise(&daemon, ROTA_SERVICE, rota.0, rota.1);
+    if mdns.advertise_http && SETTINGS.serve_http {
+        result = result.and_then(|_| advertise(&daemon, HTTP_SERVICE, SETTINGS.http_port, false));
+    }
+    if mdns.discover_devices {
+        result = result.and_then(|_| discover(&daemon));
+    }
+    if let Err(e) = result {
+        error!(error = %e, "Error setting up mDNS.");
+    }
+    *DAEMON.lock().unwrap() = Some(daemon);
+}
@@ -0,0 +1,118 @@
+use actix_web::HttpRequest;
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+                 Registry, TextEncoder};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::load_deice_config;
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref CHECKS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("rota_update_checks_total", "Authenticated update checks by result."), &["route", "result"]).unwrap());
+    static ref DOWNLOADS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("rota_firmware_downloads_total", "Firmware images sent, by target and version."), &["target", "version"]).unwrap());
+    static ref NOT_MODIFIED: IntCounter = register(IntCounter::new(
+        "rota_not_modified_total", "Requests answered 304 Not Modified.").unwrap());
+    static ref AUTH_FAILURES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("rota_auth_failures_total", "Refused device and admin requests, by reason."), &["reason"]).unwrap());
+    static ref ERRORS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("rota_errors_total", "Requests that failed with a server error."), &["route"]).unwrap());
+    static ref BYTES_SERVED: IntCounter = register(IntCounter::new(
+        "rota_firmware_bytes_served_total", "Bytes of firmware sent to devices.").unwrap());
+    static ref LATENCY: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("rota_request_duration_seconds", "Request latency by route and status."), &["route", "status"]).unwrap());
+    static ref REGISTERED_DEVICES: IntGauge = register(IntGauge::new(
+        "rota_registered_devices", "Devices in the device registry.").unwrap());
+    static ref DEVICES_PER_VERSION: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("rota_devices_per_firmware_version", "Devices by the firmware version they last reported."), &["version"]).unwrap());
+    // The firmware version each device reported in its last check, by MAC.
+    static ref REPORTED_VERSIONS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+// This function registers every metric up front, so each is exported from the first scrape on.
+pub fn init() {
+    lazy_static::initialize(&CHECKS);
+    lazy_static::initialize(&DOWNLOADS);
+    lazy_static::initialize(&NOT_MODIFIED);
+    lazy_static::initialize(&AUTH_FAILURES);
+    lazy_static::initialize(&ERRORS);
+    lazy_static::initialize(&BYTES_SERVED);
+    lazy_static::initialize(&LATENCY);
+    lazy_static::initialize(&REGISTERED_DEVICES);
+    lazy_static::initialize(&DEVICES_PER_VERSION);
+}
+
+// This function registers a metric with the registry served on `/metrics`.
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY.register(Box::new(metric.clone())).expect("metric names are unique");
+    metric
+}
+
+// This function counts an authenticated update check. `update` is whether a newer firmware is available.
+pub fn record_check(route: &str, update: bool) {
+    CHECKS.with_label_values(&[route, if update { "update_available" } else { "up_to_date" }]).inc();
+    if !update {
+        NOT_MODIFIED.inc();
+    }
+}
+
+// This function counts a firmware image sent to a device.
+pub fn record_download(target: &str, version: &str, bytes: usize) {
+    DOWNLOADS.with_label_values(&[target, version]).inc();
+    BYTES_SERVED.inc_by(bytes as u64);
+}
+
+// This function counts a refused request by its reason code.
+pub fn record_auth_failure(reason: &str) {
+    AUTH_FAILURES.with_label_values(&[reason]).inc();
+}
+
+// This function remembers the firmware version a device reported, for the devices per version gauge.
+pub fn record_device_version(mac: &str, version: &str) {
+    REPORTED_VERSIONS.lock().unwrap().insert(mac.to_uppercase(), version.to_string());
+}
+
+// The label of routes whose paths carry parameters, put in the request extensions by their scope, so a label is not
+// created per path.
+pub struct RouteLabel(pub &'static str);
+
+// This function names the route a request matched for labels: its `RouteLabel`, the path of a fixed route, one label
+// for the dashboard files and `other` for unknown routes, so scanners cannot create a series per path.
+pub fn route_label(req: &HttpRequest) -> String {
+    if let Some(label) = req.extensions().get::<RouteLabel>() {
+        return label.0.to_string()
+    }
+    let path = req.path();
+    if !req.resource_map().has_resource(path) {
+        String::from("other")
+    } else if path.starts_with("/dashboard/") {
+        String::from("/dashboard")
+    } else {
+        path.to_string()
+    }
+}
+
+// This function records the latency of a completed request and counts server errors.
+pub fn observe_request(route: &str, status: u16, seconds: f64) {
+    let histogram: Histogram = LATENCY.with_label_values(&[route, &status.to_string()]);
+    histogram.observe(seconds);
+    if status >= 500 {
+        ERRORS.with_label_values(&[route]).inc();
+    }
+}
+
+// This function renders every metric in the Prometheus text format. Device gauges are computed at scrape time.
+pub fn render() -> Result<String, Box<dyn std::error::Error>> {
+    match load_deice_config() {
+        Ok(devices) => REGISTERED_DEVICES.set(devices.len() as i64),
+        Err(e) => tracing::error!(error = %e, "Error loading device configuration for metrics."),
+    }
+    DEVICES_PER_VERSION.reset();
+    for version in REPORTED_VERSIONS.lock().unwrap().values() {
+        DEVICES_PER_VERSION.with_label_values(&[version]).inc();
+    }
+    let mut buffer = vec!();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
@@ -0,0 +1,349 @@
+use chrono::{DateTime, Utc};
+use rumqttc::{Client, Event as MqttEvent, MqttOptions, Packet, QoS};
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+use crate::events::Event;
+use crate::fleet::{self, FleetDevice};
+use crate::settings::SETTINGS;
+use crate::{find_device, parse_version_str, rollout, unassigned};
+
+// Requests the client may queue while the broker is unreachable.
+const QUEUE_CAPACITY: usize = 256;
+// How long to wait before reconnecting after the connection to the broker failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    // The connection to the broker. `None` unless MQTT is enabled.
+    static ref CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+}
+
+// The retained message telling devices that firmware newer than theirs is available.
+#[derive(Serialize, Clone)]
+struct UpdateNotice<'a> {
+    target: &'a str,
+    version: DateTime<Utc>,
+    path: &'a str,
+}
+
+// This function makes a name safe to use as one topic level, since `/`, `+` and `#` have a meaning in topics.
+fn topic_level(name: &str) -> String {
+    name.replace(['/', '+', '#'], "_")
+}
+
+// This function returns the topic a device is notified on, `<prefix>/devices/<MAC>/update`.
+fn device_topic(prefix: &str, mac: &str) -> String {
+    format!("{}/devices/{}/update", prefix, topic_level(&mac.to_uppercase()))
+}
+
+// This function returns the topic the devices of a group are notified on, `<prefix>/groups/<group>/update`.
+fn group_topic(prefix: &str, group: &str) -> String {
+    format!("{}/groups/{}/update", prefix, topic_level(group))
+}
+
+// This function returns the topic filter device status reports are received on.
+fn status_filter() -> String {
+    format!("{}/devices/+/status", SETTINGS.mqtt.topic_prefix)
+}
+
+// This function extracts the MAC from a status topic, `<prefix>/devices/<MAC>/status`.
+fn status_topic_mac<'a>(prefix: &str, topic: &'a str) -> Option<&'a str> {
+    topic.strip_prefix(prefix)?.strip_prefix("/devices/")?.strip_suffix("/status").filter(|mac| !mac.contains('/'))
+}
+
+// This function parses a status report. Devices send `{"version": "..."}` or just the version, in the format of their
+// version header or as RFC 3339.
+fn parse_status(payload: &[u8]) -> Option<DateTime<Utc>> {
+    let text = std::str::from_utf8(payload).ok()?.trim();
+    let version = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(json) => json.get("version")?.as_str()?.to_string(),
+        _ => text.to_string(),
+    };
+    parse_version_str(&version).or_else(|| DateTime::parse_from_rfc3339(&version).ok().map(|v| v.with_timezone(&Utc)))
+}
+
+// This function checks whether a device should be told about the firmware of a target: it is assigned the target, its
+// rollout includes it and it has not reported running that firmware already.
+fn is_due(device: &FleetDevice, target: &str) -> bool {
+    device.target == target
+        && device.latest_version.is_some()
+        && device.reported_version.map(|running| Some(running) < device.latest_version).unwrap_or(true)
+        && rollout::includes(target, &device.mac)
+}
+
+// This function works out the notices for the devices that are due the firmware of a target, or only for `mac`: one on
+// each device topic, and one on the topic of each group whose members are all due, so a group notice never reaches a
+// device on another target or outside the rollout.
+fn notices<'a>(prefix: &str, devices: &[FleetDevice], target: &'a str, mac: Option<&str>, due: impl Fn(&FleetDevice) -> bool)
+    -> Vec<(String, UpdateNotice<'a>)> {
+    let mut notices = vec!();
+    let mut groups = BTreeSet::new();
+    for device in devices.iter().filter(|d| mac.map(|mac| d.mac.eq_ignore_ascii_case(mac)).unwrap_or(true)) {
+        let version = match device.latest_version {
+            Some(version) if due(device) => version,
+            _ => continue
+        };
+        let notice = UpdateNotice { target, version, path: "/ota" };
+        notices.push((device_topic(prefix, &device.mac), notice.clone()));
+        if device.group != unassigned() && groups.insert(device.group.as_str())
+            && devices.iter().filter(|d| d.group == device.group).all(&due) {
+            notices.push((group_topic(prefix, &device.group), notice));
+        }
+    }
+    notices
+}
+
+// This function publishes a retained update notice.
+fn publish_notice(client: &Client, topic: &str, notice: &UpdateNotice) {
+    match serde_json::to_vec(notice) {
+        Ok(payload) => match client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+            Ok(_) => debug!(topic = %topic, target = notice.target, "Published update notice."),
+            Err(e) => warn!(topic = %topic, error = %e, "Error publishing update notice."),
+        },
+        Err(e) => error!(error = %e, "Error serializing update notice."),
+    }
+}
+
+// This function clears the retained notices a device was sent once it runs the firmware, so it is not woken by them
+// on every connect. The notice of its group goes too, as not every member is due any more.
+fn clear_notices(client: &Client, prefix: &str, device: &FleetDevice) {
+    let mut topics = vec!(device_topic(prefix, &device.mac));
+    if device.group != unassigned() {
+        topics.push(group_topic(prefix, &device.group));
+    }
+    for topic in topics {
+        // An empty retained message removes the one the broker holds.
+        match client.try_publish(topic.as_str(), QoS::AtLeastOnce, true, Vec::new()) {
+            Ok(_) => debug!(topic = %topic, "Cleared update notice."),
+            Err(e) => warn!(topic = %topic, error = %e, "Error clearing update notice."),
+        }
+    }
+}
+
+// This function tells the devices that are due the firmware of a target, or only `mac`, on their device topics and the
+// topics of their groups. The notices are retained, so devices that are asleep receive them when they next connect.
+fn notify(client: &Client, target: &str, mac: Option<&str>) {
+    let devices = match fleet::list_fleet() {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!(error = %e, "Error loading fleet for MQTT notices.");
+            return
+        }
+    };
+    let notices = notices(&SETTINGS.mqtt.topic_prefix, &devices, target, mac, |device| is_due(device, target));
+    for (topic, notice) in notices.iter() {
+        publish_notice(client, topic, notice);
+    }
+    info!(target, notices = notices.len(), "Sent MQTT update notices.");
+}
+
+// This function notifies devices when firmware becomes available to them: a new upload for their target, a rollout
+// resumed or widened, or a new target assigned. The notices are sent from their own thread, as working out which
+// devices are due reads the fleet and rollout files.
+pub fn handle(event: &Event) {
+    let client = match CLIENT.lock().unwrap().as_ref() {
+        Some(client) => client.clone(),
+        _ => return
+    };
+    let (target, mac) = match event {
+        Event::FirmwareUploaded { target, .. } => (target.to_string(), None),
+        Event::RolloutChanged { target, paused: false, .. } => (target.to_string(), None),
+        Event::Assigned { mac, field: "target", value } => (value.to_string(), Some(mac.to_string())),
+        _ => return
+    };
+    std::thread::spawn(move || notify(&client, &target, mac.as_deref()));
+}
+
+// This function records a status report received from a registered device.
+fn handle_status(topic: &str, payload: &[u8]) {
+    let mac = match status_topic_mac(&SETTINGS.mqtt.topic_prefix, topic) {
+        Some(mac) if find_device(mac).is_some() => mac,
+        _ => {
+            debug!(topic, "Ignoring status of an unknown device.");
+            return
+        }
+    };
+    let version = match parse_status(payload) {
+        Some(version) => version,
+        _ => {
+            warn!(mac, "Ignoring malformed MQTT status report.");
+            return
+        }
+    };
+    if let Err(e) = fleet::record_report(mac, version) {
+        error!(mac, error = %e, "Error saving device_status.toml.");
+        return
+    }
+    debug!(mac, version = %version, "Device reported its version over MQTT.");
+    let device = fleet::list_fleet().ok().and_then(|devices| devices.into_iter().find(|d| d.mac.eq_ignore_ascii_case(mac)));
+    let client = CLIENT.lock().unwrap().clone();
+    if let (Some(device), Some(client)) = (device, client) {
+        if !is_due(&device, &device.target) {
+            clear_notices(&client, &SETTINGS.mqtt.topic_prefix, &device);
+        }
+    }
+}
+
+// This function connects to the broker if MQTT is enabled. The connection is driven from its own thread, which
+// reconnects after failures and subscribes to device status reports each time it is connected.
+pub fn start() {
+    let mqtt = &SETTINGS.mqtt;
+    if !mqtt.enabled {
+        return
+    }
+    let mut options = MqttOptions::new(mqtt.client_id.as_str(), mqtt.host.as_str(), mqtt.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if !mqtt.username.is_empty() {
+        options.set_credentials(mqtt.username.as_str(), mqtt.password.as_str());
+    }
+    let (client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+    *CLIENT.lock().unwrap() = Some(client.clone());
+    std::thread::spawn(move || {
+        for notification in connection.iter() {
+            match notification {
+                Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                    info!(host = %mqtt.host, port = mqtt.port, "Connected to MQTT broker.");
+                    if mqtt.subscribe_status {
+                        if let Err(e) = client.try_subscribe(status_filter(), QoS::AtLeastOnce) {
+                            warn!(error = %e, "Error subscribing to device status.");
+                        }
+                    }
+                }
+                Ok(MqttEvent::Incoming(Packet::Publish(publish))) => handle_status(&publish.topic, &publish.payload),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(host = %mqtt.host, port = mqtt.port, error = %e, "MQTT connection failed, reconnecting.");
+                    std::thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clear_notices, notices, parse_status, publish_notice, status_topic_mac, topic_level};
+    use crate::fleet::FleetDevice;
+    use chrono::{TimeZone, Utc};
+    use rumqttc::{Client, MqttOptions};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{self, Sender};
+    use std::time::Duration;
+
+    fn device(mac: &str, group: &str, target: &str) -> FleetDevice {
+        FleetDevice {
+            mac: mac.to_string(),
+            alias: String::new(),
+            group: group.to_string(),
+            target: target.to_string(),
+            latest_version: Some(Utc.ymd(2026, 10, 8).and_hms(12, 0, 5)),
+            reported_version: None,
+            last_seen: None,
+            last_ip: None,
+            status: None,
+            cohort: 0,
+        }
+    }
+
+    // A broker stand-in that acknowledges one client and passes on every message it publishes as (topic, payload,
+    // retain).
+    fn broker(published: Sender<(String, Vec<u8>, bool)>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Some((header, body)) = read_packet(&mut stream) {
+                match header >> 4 {
+                    1 => stream.write_all(&[0x20, 2, 0, 0]).unwrap(),
+                    3 => {
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+                        let mut payload = &body[2 + topic_len..];
+                        if (header >> 1) & 3 > 0 {
+                            stream.write_all(&[0x40, 2, payload[0], payload[1]]).unwrap();
+                            payload = &payload[2..];
+                        }
+                        let _ = published.send((topic, payload.to_vec(), header & 1 == 1));
+                    }
+                    8 => stream.write_all(&[0x90, 3, body[0], body[1], 1]).unwrap(),
+                    12 => stream.write_all(&[0xd0, 0]).unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        port
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).ok()?;
+        let header = byte[0];
+        let (mut length, mut shift) = (0usize, 0);
+        loop {
+            stream.read_exact(&mut byte).ok()?;
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).ok()?;
+        Some((header, body))
+    }
+
+    #[test]
+    fn group_notices_wait_for_every_member() {
+        let devices = vec!(device("AA", "lab", "fw1"), device("BB", "lab", "fw1"), device("CC", "lab", "fw2"),
+                           device("DD", "desk", "fw1"), device("EE", "UNASSIGNED", "fw1"));
+        let topics = |mac| notices("rota", &devices, "fw1", mac, |d| d.target == "fw1").into_iter()
+            .map(|(topic, _)| topic).collect::<Vec<_>>();
+        assert_eq!(topics(None), vec!("rota/devices/AA/update", "rota/devices/BB/update", "rota/devices/DD/update",
+                                      "rota/groups/desk/update", "rota/devices/EE/update"));
+        assert_eq!(topics(Some("dd")), vec!("rota/devices/DD/update", "rota/groups/desk/update"));
+    }
+
+    #[test]
+    fn reported_devices_have_their_notices_cleared() {
+        let (sender, receiver) = mpsc::channel();
+        let port = broker(sender);
+        let (client, mut connection) = Client::new(MqttOptions::new("rota-test", "127.0.0.1", port), 10);
+        std::thread::spawn(move || for _ in connection.iter() {});
+
+        let devices = vec!(device("AA", "desk", "fw1"));
+        for (topic, notice) in notices("rota", &devices, "fw1", None, |_| true).iter() {
+            publish_notice(&client, topic, notice);
+        }
+        clear_notices(&client, "rota", &devices[0]);
+
+        let published = (0..4).map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap()).collect::<Vec<_>>();
+        let notice = br#"{"target":"fw1","version":"2026-10-08T12:00:05Z","path":"/ota"}"#.to_vec();
+        assert_eq!(published, vec!(
+            ("rota/devices/AA/update".to_string(), notice.clone(), true),
+            ("rota/groups/desk/update".to_string(), notice, true),
+            ("rota/devices/AA/update".to_string(), vec!(), true),
+            ("rota/groups/desk/update".to_string(), vec!(), true),
+        ));
+    }
+
+    #[test]
+    fn status_topics_name_the_device() {
+        assert_eq!(status_topic_mac("rota", "rota/devices/AA:BB:CC:DD:EE:FF/status"), Some("AA:BB:CC:DD:EE:FF"));
+        assert_eq!(status_topic_mac("rota", "rota/devices/a/b/status"), None);
+        assert_eq!(status_topic_mac("rota", "other/devices/AA:BB:CC:DD:EE:FF/status"), None);
+        assert_eq!(topic_level("lab/+#"), "lab___");
+    }
+
+    #[test]
+    fn status_reports_carry_the_running_version() {
+        let expected = "2026-10-08T12:00:05Z";
+        for payload in [r#"{"version": "Oct  8 2026 12:00:05"}"#, "Oct  8 2026 12:00:05", expected].iter() {
+            assert_eq!(parse_status(payload.as_bytes()).unwrap().to_rfc3339(), "2026-10-08T12:00:05+00:00", "{}", payload);
+        }
+        assert!(parse_status(b"{\"version\": 3}").is_none());
+        assert!(parse_status(b"garbage").is_none());
+    }
+}
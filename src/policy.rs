@@ -0,0 +1,154 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+use crate::proxy::ClientInfo;
+use crate::securitylog;
+use crate::settings::SETTINGS;
+use tracing::{error, warn};
+use crate::{find_device, store};
+
+lazy_static! {
+    // Serializes read-modify-write cycles on the insecure device list. Holds the requests not written to it yet, keyed
+    // by upper case MAC.
+    static ref INSECURE_DEVICES_LOCK: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+// What to do with a device that authenticates over plain HTTP.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpsPolicy {
+    // Serve the request and log a warning.
+    Warn,
+    // Refuse the request with 403 Forbidden.
+    Reject,
+    // Refuse the request with 426 Upgrade Required.
+    Upgrade,
+}
+
+impl HttpsPolicy {
+    // This function parses a policy name from `rota.toml`.
+    fn parse(name: &str) -> Option<HttpsPolicy> {
+        match name.to_ascii_lowercase().as_str() {
+            "warn" => Some(HttpsPolicy::Warn),
+            "reject" => Some(HttpsPolicy::Reject),
+            "upgrade" => Some(HttpsPolicy::Upgrade),
+            _ => None,
+        }
+    }
+}
+
+// A device seen sending its credentials over plain HTTP, as stored in `insecure_devices.toml`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InsecureDevice {
+    pub mac: String,
+    pub last_ip: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub requests: u64,
+    pub refused: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct InsecureDeviceStore {
+    devices: Vec<InsecureDevice>,
+}
+
+// This function looks up the policy for a device. A device setting wins over its group's, which wins over the default.
+// Keys are compared case-insensitively since the settings loader lower cases them.
+pub fn policy_for(mac: &str) -> HttpsPolicy {
+    let policy = &SETTINGS.https_policy;
+    let lookup = |map: &std::collections::HashMap<String, String>, key: &str| {
+        map.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).and_then(|(_, v)| HttpsPolicy::parse(v))
+    };
+    if let Some(device_policy) = lookup(&policy.devices, mac) {
+        return device_policy
+    }
+    let group = find_device(mac).map(|d| d.device_group);
+    if let Some(group_policy) = group.and_then(|group| lookup(&policy.groups, &group)) {
+        return group_policy
+    }
+    HttpsPolicy::parse(&policy.default).unwrap_or(HttpsPolicy::Warn)
+}
+
+// This function applies the HTTPS policy to an authenticated device request. Requests over plain HTTP are recorded and
+// a response is returned if the policy refuses them.
+pub fn enforce(req: &HttpRequest, mac: &str) -> Option<HttpResponse> {
+    let client = ClientInfo::of(req);
+    if client.https {
+        return None
+    }
+    let policy = policy_for(mac);
+    if let Err(e) = record_insecure_device(mac, &client.ip_string(), policy != HttpsPolicy::Warn) {
+        error!(error = %e, "Error saving insecure_devices.toml.");
+    }
+    match policy {
+        HttpsPolicy::Warn => {
+            warn!(policy = "warn", "Client is sending its API key over an unencrypted HTTP request.");
+            None
+        }
+        HttpsPolicy::Reject => {
+            warn!(decision = "reject", policy = "reject", "Refused client sending its API key over an unencrypted HTTP request.");
+            securitylog::record(403, "https_required", &client.ip_string(), Some(mac), req.path());
+            Some(HttpResponse::Forbidden().header("x-rota-reason", "https_required").body("https_required"))
+        }
+        HttpsPolicy::Upgrade => {
+            warn!(decision = "reject", policy = "upgrade", "Asked client to upgrade to HTTPS.");
+            Some(HttpResponse::build(StatusCode::UPGRADE_REQUIRED)
+                .header("upgrade", "TLS/1.2, HTTP/1.1")
+                .header("connection", "Upgrade")
+                .header("x-rota-reason", "https_required")
+                .body("https_required"))
+        }
+    }
+}
+
+// The insecure device list in the configuration directory.
+const INSECURE_DEVICES: &str = "insecure_devices.toml";
+
+// This function loads the devices seen using plain HTTP.
+fn load_insecure_devices() -> Result<Vec<InsecureDevice>, Box<dyn Error>> {
+    Ok(store::load::<InsecureDeviceStore>(INSECURE_DEVICES)?.devices)
+}
+
+// This function lists the devices seen using plain HTTP.
+pub fn list_insecure_devices() -> Result<Vec<InsecureDevice>, Box<dyn Error>> {
+    let unsaved = INSECURE_DEVICES_LOCK.lock().unwrap();
+    let mut devices = load_insecure_devices()?;
+    for device in devices.iter_mut() {
+        device.requests += unsaved.get(&device.mac.to_uppercase()).copied().unwrap_or(0);
+    }
+    Ok(devices)
+}
+
+// This function records that a device used plain HTTP, so boards still running insecure update code can be found. A
+// device seen again within a minute from the same address under the same policy is only counted in memory, to avoid
+// rewriting the list on every single request.
+fn record_insecure_device(mac: &str, ip: &str, refused: bool) -> Result<(), Box<dyn Error>> {
+    let mut unsaved = INSECURE_DEVICES_LOCK.lock().unwrap();
+    let mut devices = load_insecure_devices()?;
+    let now = Utc::now();
+    match devices.iter_mut().find(|d| d.mac.eq_ignore_ascii_case(mac)) {
+        Some(device) if device.last_ip == ip && device.refused == refused && now - device.last_seen <= Duration::minutes(1) => {
+            *unsaved.entry(mac.to_uppercase()).or_insert(0) += 1;
+            return Ok(())
+        }
+        Some(device) => {
+            device.last_ip = ip.to_string();
+            device.last_seen = now;
+            device.requests += 1 + unsaved.remove(&mac.to_uppercase()).unwrap_or(0);
+            device.refused = refused;
+        }
+        _ => devices.push(InsecureDevice {
+            mac: mac.to_string(),
+            last_ip: ip.to_string(),
+            first_seen: now,
+            last_seen: now,
+            requests: 1,
+            refused,
+        }),
+    }
+    store::save(INSECURE_DEVICES, &InsecureDeviceStore { devices })
+}
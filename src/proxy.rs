@@ -0,0 +1,191 @@
+use actix_web::dev::{Payload, ServiceRequest};
+use actix_web::http::HeaderMap;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ok, Ready};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::settings::SETTINGS;
+use crate::tls::TlsConnection;
+
+lazy_static! {
+    // The `trusted_proxies` setting parsed into networks. Invalid entries are reported once and skipped.
+    static ref TRUSTED_PROXIES: Vec<IpNet> = SETTINGS.trusted_proxies.iter().filter_map(|cidr| {
+        // A bare address is accepted as a single host network.
+        match cidr.parse::<IpNet>().or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from)) {
+            Ok(net) => Some(net),
+            Err(_) => {
+                tracing::warn!(proxy = %cidr, "Ignoring invalid trusted proxy.");
+                None
+            }
+        }
+    }).collect();
+}
+
+// The client behind a request. Forwarded headers are only believed when the socket peer is a trusted proxy, otherwise
+// the socket peer itself is the client. Use it as a handler argument or through `ClientInfo::of`.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub https: bool,
+}
+
+impl ClientInfo {
+    // This function resolves the client of a request, caching the result in the request extensions.
+    pub fn of(req: &HttpRequest) -> ClientInfo {
+        ClientInfo::cached(req, req.peer_addr())
+    }
+
+    // This function resolves the client of a request seen by a middleware.
+    pub fn of_service_request(req: &ServiceRequest) -> ClientInfo {
+        ClientInfo::cached(req, req.peer_addr())
+    }
+
+    fn cached<M: HttpMessage>(msg: &M, peer: Option<SocketAddr>) -> ClientInfo {
+        if let Some(info) = msg.extensions().get::<ClientInfo>() {
+            return info.clone()
+        }
+        let info = resolve(peer, msg.headers(), msg.extensions().get::<TlsConnection>().is_some());
+        msg.extensions_mut().insert(info.clone());
+        info
+    }
+
+    // This function renders the client IP for logs.
+    pub fn ip_string(&self) -> String {
+        self.ip.map(|ip| ip.to_string()).unwrap_or_else(|| String::from("unknown"))
+    }
+}
+
+impl FromRequest for ClientInfo {
+    type Error = Error;
+    type Future = Ready<Result<ClientInfo, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ok(ClientInfo::of(req))
+    }
+}
+
+// This function parses an address as found in forwarding headers, e.g. `1.2.3.4`, `1.2.3.4:5678`, `"[2001:db8::1]:80"`.
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    value.parse::<IpAddr>().ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| value.trim_start_matches('[').split(']').next()?.parse().ok())
+}
+
+// This function returns the `for` and `proto` parameters of each element of the RFC 7239 `Forwarded` header.
+fn parse_forwarded(value: &str) -> Vec<(Option<IpAddr>, Option<String>)> {
+    value.split(',').map(|element| {
+        let mut for_ip = None;
+        let mut proto = None;
+        for pair in element.split(';') {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next().map(|k| k.trim().to_ascii_lowercase()), kv.next()) {
+                (Some(ref k), Some(v)) if k == "for" => for_ip = parse_forwarded_ip(v),
+                (Some(ref k), Some(v)) if k == "proto" => proto = Some(v.trim().trim_matches('"').to_ascii_lowercase()),
+                _ => {}
+            }
+        }
+        (for_ip, proto)
+    }).collect()
+}
+
+// This function walks a proxy chain from the closest hop outwards and returns the first hop whose address is not a
+// trusted proxy. Every hop after the socket peer appended itself, so only the untrusted end of the chain can be spoofed.
+fn first_untrusted<'a, T>(chain: &'a [T], trusted: &[IpNet], ip: impl Fn(&T) -> IpAddr) -> Option<&'a T> {
+    chain.iter().rev().find(|hop| !trusted.iter().any(|net| net.contains(&ip(hop)))).or_else(|| chain.first())
+}
+
+// This function resolves the client address and scheme from the socket peer and, if the peer is a trusted proxy, the
+// `Forwarded`, `X-Forwarded-For`, `X-Real-IP` and `X-Forwarded-Proto` headers.
+pub fn resolve(peer: Option<SocketAddr>, headers: &HeaderMap, tls: bool) -> ClientInfo {
+    resolve_with(peer, headers, tls, &TRUSTED_PROXIES)
+}
+
+fn resolve_with(peer: Option<SocketAddr>, headers: &HeaderMap, tls: bool, trusted: &[IpNet]) -> ClientInfo {
+    let peer_ip = peer.map(|addr| addr.ip());
+    let trusted_peer = peer_ip.map(|ip| trusted.iter().any(|net| net.contains(&ip))).unwrap_or(false);
+    if !trusted_peer {
+        return ClientInfo { ip: peer_ip, https: tls }
+    }
+    let header = |name: &str| headers.get(name).and_then(|h| h.to_str().ok());
+    let mut ip = None;
+    let mut proto = None;
+    if let Some(forwarded) = header("forwarded") {
+        // The scheme is taken from the same element as the address, the one added by the proxy the client connected
+        // to, as the client can put anything in the elements before it.
+        let hops: Vec<(IpAddr, Option<String>)> = parse_forwarded(forwarded).into_iter()
+            .filter_map(|(ip, proto)| ip.map(|ip| (ip, proto)))
+            .collect();
+        if let Some((hop_ip, hop_proto)) = first_untrusted(&hops, trusted, |hop| hop.0) {
+            ip = Some(*hop_ip);
+            proto = hop_proto.clone();
+        }
+    }
+    if ip.is_none() {
+        if let Some(forwarded_for) = header("x-forwarded-for") {
+            let chain: Vec<IpAddr> = forwarded_for.split(',').filter_map(parse_forwarded_ip).collect();
+            ip = first_untrusted(&chain, trusted, |hop| *hop).cloned();
+        }
+    }
+    if ip.is_none() {
+        ip = header("x-real-ip").and_then(parse_forwarded_ip);
+    }
+    if proto.is_none() {
+        proto = header("x-forwarded-proto").map(|p| p.trim().to_ascii_lowercase());
+    }
+    ClientInfo {
+        ip: ip.or(peer_ip),
+        https: tls || proto.map(|p| p == "https").unwrap_or(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_with;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use actix_web::http::HeaderMap;
+    use ipnet::IpNet;
+    use std::net::{IpAddr, SocketAddr};
+
+    // This function resolves a request from `peer` carrying `headers`, trusting the proxies in 10.0.0.0/8.
+    fn resolve(peer: &str, headers: &[(&'static str, &str)]) -> (IpAddr, bool) {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+        }
+        let trusted: Vec<IpNet> = vec!("10.0.0.0/8".parse().unwrap());
+        let info = resolve_with(Some(SocketAddr::new(peer.parse().unwrap(), 443)), &map, false, &trusted);
+        (info.ip.unwrap(), info.https)
+    }
+
+    #[test]
+    fn ignores_forwarded_headers_from_untrusted_peers() {
+        let headers = [("forwarded", "for=198.51.100.7;proto=https"), ("x-forwarded-for", "198.51.100.7"), ("x-forwarded-proto", "https")];
+        assert_eq!(resolve("203.0.113.5", &headers), ("203.0.113.5".parse().unwrap(), false));
+    }
+
+    #[test]
+    fn takes_the_client_the_nearest_trusted_proxy_saw() {
+        let forwarded = [("forwarded", "for=192.0.2.1, for=198.51.100.7;proto=https, for=10.0.0.2;proto=http")];
+        assert_eq!(resolve("10.0.0.1", &forwarded), ("198.51.100.7".parse().unwrap(), true));
+        let forwarded_for = [("x-forwarded-for", "192.0.2.1, 198.51.100.7, 10.0.0.2"), ("x-forwarded-proto", "https")];
+        assert_eq!(resolve("10.0.0.1", &forwarded_for), ("198.51.100.7".parse().unwrap(), true));
+    }
+
+    #[test]
+    fn ignores_the_scheme_claimed_by_the_client() {
+        let headers = [("forwarded", "for=192.0.2.1;proto=https, for=198.51.100.7;proto=http")];
+        assert_eq!(resolve("10.0.0.1", &headers), ("198.51.100.7".parse().unwrap(), false));
+        let appended = [("forwarded", "proto=https, for=198.51.100.7")];
+        assert_eq!(resolve("10.0.0.1", &appended), ("198.51.100.7".parse().unwrap(), false));
+    }
+
+    #[test]
+    fn falls_back_to_the_outermost_hop_when_every_hop_is_trusted() {
+        let headers = [("forwarded", "for=\"10.0.0.4:80\", for=10.0.0.3"), ("x-real-ip", "192.0.2.1")];
+        assert_eq!(resolve("10.0.0.1", &headers).0, "10.0.0.4".parse::<IpAddr>().unwrap());
+        assert_eq!(resolve("10.0.0.1", &[("x-real-ip", "192.0.2.1")]).0, "192.0.2.1".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,314 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::settings::{RateLimit, SETTINGS};
+use tracing::warn;
+
+lazy_static! {
+    // Buckets, failed attempts and bans of every client seen recently. Kept in memory, a restart lifts all bans.
+    static ref LIMITER: Mutex<Limiter> = Mutex::new(Limiter::default());
+}
+
+// Devices are rate limited both by address and by the MAC they claim, so neither rotating addresses nor rotating MACs
+// helps. Failed authentications only count against addresses: the MAC is claimed before the device authenticates, so
+// anyone knowing it could otherwise get the device banned. Admin clients are tracked by address separately, so a
+// misbehaving device does not lock out an operator on its network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKind {
+    Ip,
+    Mac,
+    Admin,
+}
+
+// A token bucket. It holds up to `burst` tokens and refills at the configured rate per minute.
+struct Bucket {
+    tokens: f64,
+    updated: DateTime<Utc>,
+}
+
+// A temporary ban after too many failed authentication attempts.
+#[derive(Clone, Serialize)]
+pub struct Ban {
+    pub kind: ClientKind,
+    pub client: String,
+    pub since: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    pub failures: usize,
+}
+
+// A client with failed authentication attempts that is not banned yet.
+#[derive(Serialize)]
+pub struct FailureCount {
+    pub kind: ClientKind,
+    pub client: String,
+    pub failures: usize,
+}
+
+// The limiter state as reported by the `/ratelimits` admin route.
+#[derive(Serialize)]
+pub struct Status {
+    pub enabled: bool,
+    pub tracked_clients: usize,
+    pub bans: Vec<Ban>,
+    pub failures: Vec<FailureCount>,
+}
+
+// Why a request was refused.
+pub enum Refusal {
+    RateLimited { retry_after: i64 },
+    Banned { retry_after: i64 },
+}
+
+impl Refusal {
+    // This function returns the reason code sent in `x-rota-reason`.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Refusal::RateLimited { .. } => "rate_limited",
+            Refusal::Banned { .. } => "banned",
+        }
+    }
+
+    // This function turns the refusal into a 429 Too Many Requests response.
+    pub fn response(&self) -> HttpResponse {
+        let retry_after = match self {
+            Refusal::RateLimited { retry_after } | Refusal::Banned { retry_after } => *retry_after,
+        };
+        HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+            .header("retry-after", retry_after.max(1).to_string())
+            .header("x-rota-reason", self.reason())
+            .body(self.reason())
+    }
+}
+
+#[derive(Default)]
+struct Limiter {
+    buckets: HashMap<(ClientKind, String), Bucket>,
+    failures: HashMap<(ClientKind, String), Vec<DateTime<Utc>>>,
+    bans: HashMap<(ClientKind, String), Ban>,
+}
+
+impl Limiter {
+    // This function drops expired bans, failures outside the window and buckets that have refilled completely.
+    fn prune(&mut self, limits: &RateLimit, now: DateTime<Utc>) {
+        let window = Duration::seconds(limits.failure_window_secs);
+        self.bans.retain(|_, ban| ban.until > now);
+        self.failures.retain(|_, times| {
+            times.retain(|t| now - *t < window);
+            !times.is_empty()
+        });
+        self.buckets.retain(|(kind, _), bucket| {
+            let (per_minute, burst) = bucket_settings(limits, *kind);
+            refill(bucket, now, per_minute, burst) < burst as f64
+        });
+    }
+
+    // This function returns the time left on a ban of the client, if it is banned.
+    fn banned(&self, kind: ClientKind, client: &str, now: DateTime<Utc>) -> Option<i64> {
+        self.bans.get(&(kind, client.to_string())).filter(|ban| ban.until > now).map(|ban| (ban.until - now).num_seconds())
+    }
+
+    // This function takes a token from the client's bucket. Returns the seconds until a token is available if it is empty.
+    fn take(&mut self, limits: &RateLimit, kind: ClientKind, client: &str, now: DateTime<Utc>) -> Result<(), i64> {
+        let (per_minute, burst) = bucket_settings(limits, kind);
+        if per_minute == 0 {
+            return Ok(())
+        }
+        let bucket = self.buckets.entry((kind, client.to_string()))
+            .or_insert(Bucket { tokens: burst as f64, updated: now });
+        if refill(bucket, now, per_minute, burst) < 1.0 {
+            return Err(((1.0 - bucket.tokens) * 60.0 / per_minute as f64).ceil() as i64)
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    // This function checks a request of `clients` at `now`, see `check`.
+    fn check(&mut self, limits: &RateLimit, clients: &[(ClientKind, &str)], now: DateTime<Utc>) -> Result<(), Refusal> {
+        self.prune(limits, now);
+        for (kind, client) in clients {
+            if let Some(retry_after) = self.banned(*kind, client, now) {
+                return Err(Refusal::Banned { retry_after })
+            }
+        }
+        for (kind, client) in clients {
+            if let Err(retry_after) = self.take(limits, *kind, client, now) {
+                warn!(decision = "rate_limited", kind = ?kind, client = %client, retry_after, "Rate limited client.");
+                return Err(Refusal::RateLimited { retry_after })
+            }
+        }
+        Ok(())
+    }
+
+    // This function records a failed authentication attempt of `clients` at `now`, see `record_failure`.
+    fn record_failure(&mut self, limits: &RateLimit, clients: &[(ClientKind, &str)], now: DateTime<Utc>) {
+        let window = Duration::seconds(limits.failure_window_secs);
+        for (kind, client) in clients.iter().filter(|(kind, _)| *kind != ClientKind::Mac) {
+            let key = (*kind, client.to_string());
+            let times = self.failures.entry(key.clone()).or_default();
+            // Attempts outside the window must not count, even if nothing pruned them yet.
+            times.retain(|t| now - *t < window);
+            times.push(now);
+            let failures = times.len();
+            if failures >= limits.max_failed_attempts {
+                self.failures.remove(&key);
+                warn!(decision = "ban", kind = ?kind, client = %client, ban_secs = limits.ban_secs, failures, "Banning client after failed authentication attempts.");
+                self.bans.insert(key, Ban {
+                    kind: *kind,
+                    client: client.to_string(),
+                    since: now,
+                    until: now + Duration::seconds(limits.ban_secs),
+                    failures,
+                });
+            }
+        }
+    }
+
+    // This function forgets the failed attempts of `clients`.
+    fn record_success(&mut self, clients: &[(ClientKind, &str)]) {
+        for (kind, client) in clients {
+            self.failures.remove(&(*kind, client.to_string()));
+        }
+    }
+}
+
+// This function returns the rate per minute and burst size configured for a kind of client.
+fn bucket_settings(limits: &RateLimit, kind: ClientKind) -> (u32, u32) {
+    match kind {
+        ClientKind::Ip | ClientKind::Admin => (limits.ip_requests_per_minute, limits.ip_burst.max(1)),
+        ClientKind::Mac => (limits.mac_requests_per_minute, limits.mac_burst.max(1)),
+    }
+}
+
+// This function adds the tokens earned since the bucket was last updated and returns the new level.
+fn refill(bucket: &mut Bucket, now: DateTime<Utc>, per_minute: u32, burst: u32) -> f64 {
+    let elapsed = (now - bucket.updated).num_milliseconds().max(0) as f64 / 60_000.0;
+    bucket.tokens = (bucket.tokens + elapsed * per_minute as f64).min(burst as f64);
+    bucket.updated = now;
+    bucket.tokens
+}
+
+// This function checks a request against the bans and rate limits of every client key given, e.g. its address and MAC.
+// A token is only taken if none of the clients is banned.
+pub fn check(clients: &[(ClientKind, &str)]) -> Result<(), Refusal> {
+    if !SETTINGS.rate_limit.enabled {
+        return Ok(())
+    }
+    LIMITER.lock().unwrap().check(&SETTINGS.rate_limit, clients, Utc::now())
+}
+
+// This function records a failed authentication attempt and bans the client once it reaches `max_failed_attempts`
+// within the failure window. Claimed MACs are skipped, see `ClientKind`.
+pub fn record_failure(clients: &[(ClientKind, &str)]) {
+    let limits = &SETTINGS.rate_limit;
+    if !limits.enabled || limits.max_failed_attempts == 0 {
+        return
+    }
+    LIMITER.lock().unwrap().record_failure(limits, clients, Utc::now());
+}
+
+// This function forgets the failed attempts of clients that authenticated successfully.
+pub fn record_success(clients: &[(ClientKind, &str)]) {
+    LIMITER.lock().unwrap().record_success(clients);
+}
+
+// This function lifts the bans and clears the failed attempts of an address. Returns whether anything was banned.
+pub fn unban(client: &str) -> bool {
+    let mut limiter = LIMITER.lock().unwrap();
+    let mut lifted = false;
+    let keys = [
+        (ClientKind::Ip, client.to_string()),
+        (ClientKind::Admin, client.to_string()),
+    ];
+    for key in keys.iter() {
+        lifted |= limiter.bans.remove(key).is_some();
+        limiter.failures.remove(key);
+    }
+    lifted
+}
+
+// This function reports the current bans and failed attempts.
+pub fn status() -> Status {
+    let now = Utc::now();
+    let mut limiter = LIMITER.lock().unwrap();
+    limiter.prune(&SETTINGS.rate_limit, now);
+    Status {
+        enabled: SETTINGS.rate_limit.enabled,
+        tracked_clients: limiter.buckets.len(),
+        bans: limiter.bans.values().cloned().collect(),
+        failures: limiter.failures.iter()
+            .map(|((kind, client), times)| FailureCount { kind: *kind, client: client.clone(), failures: times.len() })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientKind, Limiter, Refusal};
+    use crate::settings::RateLimit;
+    use chrono::{Duration, TimeZone, Utc};
+
+    const DEVICE: [(ClientKind, &str); 2] = [(ClientKind::Ip, "192.0.2.10"), (ClientKind::Mac, "AA:BB:CC:DD:EE:FF")];
+
+    fn limits() -> RateLimit {
+        RateLimit { enabled: true, ip_requests_per_minute: 60, ip_burst: 3, max_failed_attempts: 3, ..RateLimit::default() }
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill() {
+        let limits = limits();
+        let mut limiter = Limiter::default();
+        let now = Utc.ymd(2026, 10, 18).and_hms(12, 0, 0);
+        let ip = [DEVICE[0]];
+        for _ in 0..3 {
+            assert!(limiter.check(&limits, &ip, now).is_ok());
+        }
+        match limiter.check(&limits, &ip, now) {
+            Err(Refusal::RateLimited { retry_after }) => assert_eq!(retry_after, 1),
+            _ => panic!("the fourth request should be rate limited"),
+        }
+        // One token a second at 60 per minute.
+        assert!(limiter.check(&limits, &ip, now + Duration::seconds(1)).is_ok());
+        assert!(limiter.check(&limits, &ip, now + Duration::seconds(1)).is_err());
+        // Another address has its own bucket.
+        assert!(limiter.check(&limits, &[(ClientKind::Ip, "192.0.2.11")], now).is_ok());
+    }
+
+    #[test]
+    fn failures_ban_the_address_but_not_the_mac() {
+        let limits = limits();
+        let mut limiter = Limiter::default();
+        let now = Utc.ymd(2026, 10, 18).and_hms(12, 0, 0);
+        for _ in 0..3 {
+            limiter.record_failure(&limits, &DEVICE, now);
+        }
+        match limiter.check(&limits, &DEVICE, now) {
+            Err(Refusal::Banned { retry_after }) => assert_eq!(retry_after, limits.ban_secs),
+            _ => panic!("the address should be banned"),
+        }
+        // The claimed MAC is not banned from another address, and the ban expires.
+        assert!(limiter.check(&limits, &[(ClientKind::Ip, "192.0.2.11"), DEVICE[1]], now).is_ok());
+        assert!(limiter.check(&limits, &DEVICE, now + Duration::seconds(limits.ban_secs)).is_ok());
+    }
+
+    #[test]
+    fn success_and_the_window_clear_failures() {
+        let limits = limits();
+        let mut limiter = Limiter::default();
+        let now = Utc.ymd(2026, 10, 18).and_hms(12, 0, 0);
+        limiter.record_failure(&limits, &DEVICE, now);
+        limiter.record_failure(&limits, &DEVICE, now);
+        limiter.record_success(&DEVICE);
+        limiter.record_failure(&limits, &DEVICE, now);
+        limiter.record_failure(&limits, &DEVICE, now);
+        assert!(limiter.check(&limits, &DEVICE, now).is_ok());
+        // Failures older than the window no longer count towards a ban.
+        let later = now + Duration::seconds(limits.failure_window_secs);
+        limiter.record_failure(&limits, &DEVICE, later);
+        assert!(limiter.check(&limits, &DEVICE, later).is_ok());
+        assert!(limiter.bans.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+use crate::fleet::FleetDevice;
+use crate::store;
+
+lazy_static! {
+    // Serializes read-modify-write cycles on the configuration documents.
+    static ref DEVICE_CONFIG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// What a configuration layer applies to. Layers are merged in this order, so a device's own settings override those of
+// its group, which override those of its target.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Target,
+    Group,
+    Device,
+}
+
+impl Scope {
+    pub fn parse(name: &str) -> Option<Scope> {
+        match name {
+            "target" => Some(Scope::Target),
+            "group" => Some(Scope::Group),
+            "device" => Some(Scope::Device),
+            _ => None,
+        }
+    }
+}
+
+// The settings of one target, group or device. The revision counts the changes to the layer.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConfigLayer {
+    pub revision: u32,
+    pub updated: DateTime<Utc>,
+    #[serde(serialize_with = "toml::ser::tables_last")]
+    pub values: BTreeMap<String, toml::Value>,
+}
+
+// Every configuration layer, as stored in `device_config.toml`.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ConfigStore {
+    #[serde(default)]
+    pub targets: BTreeMap<String, ConfigLayer>,
+    #[serde(default)]
+    pub groups: BTreeMap<String, ConfigLayer>,
+    #[serde(default)]
+    pub devices: BTreeMap<String, ConfigLayer>,
+}
+
+impl ConfigStore {
+    fn layers(&mut self, scope: Scope) -> &mut BTreeMap<String, ConfigLayer> {
+        match scope {
+            Scope::Target => &mut self.targets,
+            Scope::Group => &mut self.groups,
+            Scope::Device => &mut self.devices,
+        }
+    }
+}
+
+// The configuration a device is served: its merged settings, and the version it reports back to be told it is current.
+#[derive(Serialize)]
+pub struct DeviceConfig {
+    pub version: String,
+    pub config: BTreeMap<String, toml::Value>,
+}
+
+// The configuration documents in the configuration directory.
+const DEVICE_CONFIG: &str = "device_config.toml";
+
+// This function loads every configuration layer.
+pub fn load_configs() -> Result<ConfigStore, Box<dyn Error>> {
+    let _guard = DEVICE_CONFIG_LOCK.lock().unwrap();
+    store::load(DEVICE_CONFIG)
+}
+
+// This function changes the settings of one layer. `changes` is a JSON object; keys set to null are removed, and with
+// `replace` every key not in it is removed too. Devices are keyed by their uppercase MAC. Returns the new revision of
+// the layer.
+pub fn update_layer(scope: Scope, name: &str, changes: serde_json::Map<String, serde_json::Value>, replace: bool)
+    -> Result<u32, Box<dyn Error>> {
+    let name = if scope == Scope::Device { name.to_uppercase() } else { name.to_string() };
+    let _guard = DEVICE_CONFIG_LOCK.lock().unwrap();
+    let mut configs: ConfigStore = store::load(DEVICE_CONFIG)?;
+    let layer = configs.layers(scope).entry(name)
+        .or_insert_with(|| ConfigLayer { revision: 0, updated: Utc::now(), values: BTreeMap::new() });
+    if replace {
+        layer.values.clear();
+    }
+    for (key, value) in changes {
+        if value.is_null() {
+            layer.values.remove(&key);
+        } else {
+            let value = toml::Value::try_from(&value).map_err(|e| format!("invalid value for {}, {}", key, e))?;
+            layer.values.insert(key, value);
+        }
+    }
+    layer.revision += 1;
+    layer.updated = Utc::now();
+    let revision = layer.revision;
+    store::save(DEVICE_CONFIG, &configs)?;
+    Ok(revision)
+}
+
+// This function merges the layers that apply to a device. The version is derived from the merged settings, so it
+// changes whenever they do, also when the device moves to another group or target.
+pub fn config_for(device: &FleetDevice) -> Result<DeviceConfig, Box<dyn Error>> {
+    let store = load_configs()?;
+    let mut config = BTreeMap::new();
+    for layer in [store.targets.get(&device.target), store.groups.get(&device.group), store.devices.get(&device.mac.to_uppercase())]
+        .iter().flatten() {
+        config.extend(layer.values.iter().map(|(key, value)| (key.clone(), value.clone())));
+    }
+    let version = hex::encode(&Sha256::digest(&serde_json::to_vec(&config)?)[..8]);
+    Ok(DeviceConfig { version, config })
+}
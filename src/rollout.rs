@@ -0,0 +1,75 @@
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::sync::Mutex;
+
+use crate::store;
+
+lazy_static! {
+    // Serializes read-modify-write cycles on the rollout list.
+    static ref ROLLOUTS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// The rollout of a target's current firmware, as stored in `rollouts.toml`. Only devices whose cohort is below
+// `percent` are offered the update, and none while the rollout is paused. Targets without a rollout go to every device.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Rollout {
+    pub target: String,
+    pub percent: u8,
+    pub paused: bool,
+    pub updated: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct RolloutStore {
+    rollouts: Vec<Rollout>,
+}
+
+// The rollout list in the configuration directory.
+const ROLLOUTS: &str = "rollouts.toml";
+
+// This function loads the rollouts.
+fn load_rollouts() -> Result<Vec<Rollout>, Box<dyn Error>> {
+    Ok(store::load::<RolloutStore>(ROLLOUTS)?.rollouts)
+}
+
+// This function lists the rollouts.
+pub fn list_rollouts() -> Result<Vec<Rollout>, Box<dyn Error>> {
+    let _guard = ROLLOUTS_LOCK.lock().unwrap();
+    load_rollouts()
+}
+
+// This function creates or changes the rollout of a target.
+pub fn set_rollout(target: &str, percent: u8, paused: bool) -> Result<Rollout, Box<dyn Error>> {
+    let _guard = ROLLOUTS_LOCK.lock().unwrap();
+    let mut rollouts = load_rollouts()?;
+    let rollout = Rollout { target: target.to_string(), percent: percent.min(100), paused, updated: Utc::now() };
+    match rollouts.iter_mut().find(|r| r.target == target) {
+        Some(existing) => *existing = rollout.clone(),
+        _ => rollouts.push(rollout.clone()),
+    }
+    store::save(ROLLOUTS, &RolloutStore { rollouts })?;
+    Ok(rollout)
+}
+
+// This function places a device in a cohort from 0 to 99. The cohort only depends on the MAC, so raising the percentage
+// of a rollout adds devices without removing any.
+pub fn cohort(mac: &str) -> u8 {
+    let digest = Sha256::digest(mac.to_uppercase().as_bytes());
+    (u16::from_be_bytes([digest[0], digest[1]]) % 100) as u8
+}
+
+// This function decides whether a device is offered the current firmware of its target. If the rollouts cannot be
+// read, updates are held rather than risking a paused rollout going out.
+pub fn includes(target: &str, mac: &str) -> bool {
+    match list_rollouts() {
+        Ok(rollouts) => match rollouts.iter().find(|r| r.target == target) {
+            Some(rollout) => !rollout.paused && cohort(mac) < rollout.percent,
+            _ => true,
+        },
+        Err(e) => {
+            tracing::error!(error = %e, "Error loading rollouts.toml, holding updates.");
+            false
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use chrono::Utc;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::get_config_path;
+use crate::settings::SETTINGS;
+
+lazy_static! {
+    // Keeps lines from concurrent workers from interleaving.
+    static ref SECURITY_LOG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// This function returns the path of the security log, `security.log` in the configuration directory unless configured.
+fn security_log_path() -> String {
+    if SETTINGS.security_log.is_empty() {
+        format!("{}{}", get_config_path(), "security.log")
+    } else {
+        SETTINGS.security_log.clone()
+    }
+}
+
+// This function formats a security log line. The format is stable, `rota_example/fail2ban/rota.conf` depends on it:
+//   <RFC 3339 UTC time> rota[security]: status=<code> reason=<reason> ip=<client ip> mac=<MAC or -> path=<path>
+// Values never contain spaces, missing values are written as `-`.
+pub fn format_line(status: u16, reason: &str, ip: &str, mac: Option<&str>, path: &str) -> String {
+    let field = |value: &str| if value.is_empty() { String::from("-") } else { value.replace(char::is_whitespace, "_") };
+    format!("{} rota[security]: status={} reason={} ip={} mac={} path={}",
+            Utc::now().format("%Y-%m-%dT%H:%M:%SZ"), status, field(reason), field(ip), field(mac.unwrap_or("")), field(path))
+}
+
+// This function appends a refused request to the security log.
+pub fn record(status: u16, reason: &str, ip: &str, mac: Option<&str>, path: &str) {
+    // Every refusal passes through here, so this is also where they are counted and published.
+    crate::metrics::record_auth_failure(reason);
+    crate::events::publish(crate::events::Event::Rejected { status, reason, ip, mac, path });
+    let line = format_line(status, reason, ip, mac, path);
+    let _guard = SECURITY_LOG_LOCK.lock().unwrap();
+    let result = OpenOptions::new().create(true).append(true).open(security_log_path())
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(e) = result {
+        tracing::error!(error = %e, "Error writing security log.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_line;
+    use regex::Regex;
+
+    // The filter shipped for fail2ban, so a change to the log format that breaks it fails here.
+    const FILTER: &str = include_str!("../rota_example/fail2ban/rota.conf");
+
+    // This function turns a fail2ban regex from the filter into a plain regex, `<HOST>` capturing the address.
+    fn filter_regex(key: &str) -> Regex {
+        let line = FILTER.lines().find(|l| l.starts_with(key)).expect("filter defines the regex");
+        let pattern = line.split_once('=').unwrap().1.trim().replace("<HOST>", r"(?P<host>\S+)");
+        // fail2ban matches the text after the timestamp, so skip over it here.
+        Regex::new(&pattern.replacen('^', r"^\S+ ", 1)).unwrap()
+    }
+
+    #[test]
+    fn failregex_matches_authentication_failures() {
+        let fail = filter_regex("failregex");
+        for (status, reason, mac) in [(401, "invalid_api_key", Some("AA:BB:CC:DD:EE:FF")), (401, "missing_admin_token", None),
+                                      (401, "unknown_admin_token", None)].iter() {
+            let line = format_line(*status, reason, "203.0.113.7", *mac, "/ota");
+            let host = fail.captures(&line).unwrap_or_else(|| panic!("failregex does not match {}", line));
+            assert_eq!(&host["host"], "203.0.113.7");
+        }
+        let ipv6 = format_line(401, "invalid_signature", "2001:db8::1", Some("AA:BB:CC:DD:EE:FF"), "/checkforupdate");
+        assert_eq!(&fail.captures(&ipv6).unwrap()["host"], "2001:db8::1");
+    }
+
+    #[test]
+    fn failregex_ignores_other_refusals() {
+        let fail = filter_regex("failregex");
+        for (status, reason) in [(429, "rate_limited"), (403, "https_required"), (403, "unknown_device_type"),
+                                 (403, "insufficient_scope")].iter() {
+            let line = format_line(*status, reason, "203.0.113.7", Some("AA:BB:CC:DD:EE:FF"), "/ota");
+            assert!(!fail.is_match(&line), "failregex matches {}", line);
+        }
+    }
+
+    #[test]
+    fn values_never_contain_spaces() {
+        let line = format_line(401, "bad reason", "", None, "/a path");
+        assert!(line.ends_with("status=401 reason=bad_reason ip=- mac=- path=/a_path"));
+    }
+}
@@ -0,0 +1,447 @@
+use config::Config;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::commands::{MAX_EXPIRY_SECS, MAX_HISTORY_DAYS};
+use crate::get_config_path;
+
+lazy_static! {
+    // The server settings, loaded once from `rota.toml` in the configuration directory.
+    pub static ref SETTINGS: Settings = load_settings();
+}
+
+// Server wide settings read from `rota.toml`. Every field has a default so the file is optional.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    // Address the HTTP and HTTPS listeners bind to.
+    pub listen_address: String,
+    // Serve plain HTTP. Can be combined with `tls.enabled` to serve both at once.
+    pub serve_http: bool,
+    pub http_port: u16,
+    // Networks of reverse proxies whose forwarding headers (`Forwarded`, `X-Forwarded-For`, `X-Real-IP`,
+    // `X-Forwarded-Proto`) are believed. Requests from anywhere else are identified by their socket address.
+    pub trusted_proxies: Vec<String>,
+    // Accept the device API key appended to the version header as `version?key`, as older firmware sends it.
+    pub legacy_version_key: bool,
+    // File refused requests are logged to for fail2ban. Defaults to `security.log` in the configuration directory.
+    pub security_log: String,
+    pub request_signing: RequestSigning,
+    pub tls: Tls,
+    pub https_policy: HttpsPolicy,
+    pub rate_limit: RateLimit,
+    pub firmware_signing: FirmwareSigning,
+    pub encryption: Encryption,
+    pub logging: Logging,
+    pub metrics: Metrics,
+    pub dashboard: Dashboard,
+    // Endpoints fleet events are posted to, see `webhooks.rs`.
+    pub webhooks: Vec<Webhook>,
+    pub mqtt: Mqtt,
+    pub espota: Espota,
+    pub mdns: Mdns,
+    pub hawkbit: Hawkbit,
+    pub commands: Commands,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            listen_address: String::from("localhost"),
+            serve_http: true,
+            http_port: 80,
+            trusted_proxies: vec!(),
+            legacy_version_key: false,
+            security_log: String::new(),
+            request_signing: RequestSigning::default(),
+            tls: Tls::default(),
+            https_policy: HttpsPolicy::default(),
+            rate_limit: RateLimit::default(),
+            firmware_signing: FirmwareSigning::default(),
+            encryption: Encryption::default(),
+            logging: Logging::default(),
+            metrics: Metrics::default(),
+            dashboard: Dashboard::default(),
+            webhooks: vec!(),
+            mqtt: Mqtt::default(),
+            espota: Espota::default(),
+            mdns: Mdns::default(),
+            hawkbit: Hawkbit::default(),
+            commands: Commands::default(),
+        }
+    }
+}
+
+// Settings for HMAC signed device requests, see `signing.rs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RequestSigning {
+    // Secret the per-device signing keys are derived from. Signing is disabled while this is empty.
+    pub master_key: String,
+    // Reject devices that authenticate with a plain API key instead of a signature.
+    pub required: bool,
+    // How far the request timestamp may be from the server clock, in seconds.
+    pub max_clock_skew_secs: i64,
+}
+
+impl Default for RequestSigning {
+    fn default() -> RequestSigning {
+        RequestSigning {
+            master_key: String::new(),
+            required: false,
+            max_clock_skew_secs: 300,
+        }
+    }
+}
+
+// This function loads `rota.toml`, falling back to the defaults if it is missing or invalid.
+fn load_settings() -> Settings {
+    let mut settings = Config::new();
+    let path = format!("{}{}", get_config_path(), "rota.toml");
+    if Path::new(path.as_str()).exists() {
+        if let Err(e) = settings.merge(config::File::with_name(path.as_str())) {
+            eprintln!("Error merging rota.toml {}", e);
+        }
+    }
+    let mut settings: Settings = match settings.try_into() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Error parsing rota.toml, using defaults. {}", e);
+            Settings::default()
+        }
+    };
+    settings.commands.bound();
+    settings
+}
+
+// Settings for the native HTTPS listener, see `tls.rs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Tls {
+    pub enabled: bool,
+    pub port: u16,
+    // PEM encoded certificate chain and private key.
+    pub cert_path: String,
+    pub key_path: String,
+    // How often the certificate files are checked for changes, in seconds. 0 disables reloading.
+    pub reload_interval_secs: u64,
+    // PEM encoded CA that issues device client certificates. Enables mutual TLS when set.
+    pub client_ca_path: String,
+    // Refuse TLS handshakes without a client certificate.
+    pub require_client_cert: bool,
+    // Refuse ESP32 devices that do not authenticate with a client certificate, whichever listener they use.
+    pub require_esp32_client_cert: bool,
+}
+
+impl Default for Tls {
+    fn default() -> Tls {
+        Tls {
+            enabled: false,
+            port: 443,
+            cert_path: String::new(),
+            key_path: String::new(),
+            reload_interval_secs: 60,
+            client_ca_path: String::new(),
+            require_client_cert: false,
+            require_esp32_client_cert: false,
+        }
+    }
+}
+
+// What to do with devices sending credentials over plain HTTP, see `policy.rs`. Each value is `warn`, `reject` (403) or
+// `upgrade` (426). Device entries override group entries, which override the default.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct HttpsPolicy {
+    pub default: String,
+    pub groups: HashMap<String, String>,
+    pub devices: HashMap<String, String>,
+}
+
+impl Default for HttpsPolicy {
+    fn default() -> HttpsPolicy {
+        HttpsPolicy {
+            default: String::from("warn"),
+            groups: HashMap::new(),
+            devices: HashMap::new(),
+        }
+    }
+}
+
+// Token bucket rate limits and brute-force lockout, see `ratelimit.rs`. A rate of 0 disables that bucket. Off by
+// default, as behind a proxy missing from `trusted_proxies` the whole fleet would share one address.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub enabled: bool,
+    // Requests each client address may make per minute, and how many it may make at once.
+    pub ip_requests_per_minute: u32,
+    pub ip_burst: u32,
+    // Requests each device MAC may make per minute, and how many it may make at once.
+    pub mac_requests_per_minute: u32,
+    pub mac_burst: u32,
+    // Failed authentication attempts within `failure_window_secs` before an address is banned. 0 disables bans.
+    pub max_failed_attempts: usize,
+    pub failure_window_secs: i64,
+    pub ban_secs: i64,
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit {
+            enabled: false,
+            ip_requests_per_minute: 120,
+            ip_burst: 60,
+            mac_requests_per_minute: 30,
+            mac_burst: 10,
+            max_failed_attempts: 10,
+            failure_window_secs: 600,
+            ban_secs: 900,
+        }
+    }
+}
+
+// Ed25519 firmware signatures, see `firmware.rs`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct FirmwareSigning {
+    // File holding rota's base64 encoded secret key. Uploaded firmware is signed with it when set.
+    pub key_path: String,
+    // Base64 encoded public keys, e.g. of a CI pipeline, whose signatures are accepted on upload.
+    pub trusted_public_keys: Vec<String>,
+    // Refuse to store or serve firmware without a valid signature.
+    pub required: bool,
+    // Append the signature to served binaries in the ESP8266 signed update layout.
+    pub append_signature: bool,
+}
+
+// Firmware encryption at rest and per-device encrypted delivery, see `encryption.rs`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Encryption {
+    // File holding the base64 encoded AES-256 key firmware is stored with. Firmware is stored in plaintext while empty.
+    pub key_path: String,
+    // PEM encoded RSA-3072 public keys, by device MAC and by group. Devices with a key receive `esp_encrypted_img` images.
+    pub devices: HashMap<String, String>,
+    pub groups: HashMap<String, String>,
+}
+
+// Log output, see `logging.rs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Logging {
+    // `human` or `json`.
+    pub format: String,
+    // Level filter in `RUST_LOG` syntax, e.g. `info,rota::ratelimit=debug`.
+    pub filter: String,
+    // Log to stdout.
+    pub stdout: bool,
+    // Directory to also write `rota.log` to, rotated as `minutely`, `hourly`, `daily` or `never`. Disabled while empty.
+    pub directory: String,
+    pub rotation: String,
+}
+
+impl Default for Logging {
+    fn default() -> Logging {
+        Logging {
+            format: String::from("human"),
+            filter: String::from("info"),
+            stdout: true,
+            directory: String::new(),
+            rotation: String::from("daily"),
+        }
+    }
+}
+
+// The Prometheus `/metrics` endpoint, see `metrics.rs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Metrics {
+    // Require an admin token, any scope, to scrape metrics.
+    pub require_token: bool,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            require_token: true,
+        }
+    }
+}
+
+// The web dashboard, see `rota_example/dashboard`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Dashboard {
+    // Directory the dashboard is served from on `/dashboard`. Not served if empty.
+    pub directory: String,
+}
+
+// An outbound webhook, see `webhooks.rs`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Webhook {
+    pub url: String,
+    // Event types to send, e.g. `["download_completed", "rejected"]`. Every event is sent if empty.
+    pub events: Vec<String>,
+    // Key of the HMAC-SHA256 signature sent in `x-rota-signature`. Deliveries are unsigned if empty.
+    pub secret: String,
+    // Attempts before a delivery is given up, and the wait before the first retry in seconds. The wait doubles after
+    // each attempt.
+    pub max_attempts: u32,
+    pub backoff_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for Webhook {
+    fn default() -> Webhook {
+        Webhook {
+            url: String::new(),
+            events: vec!(),
+            secret: String::new(),
+            max_attempts: 5,
+            backoff_secs: 5,
+            timeout_secs: 10,
+        }
+    }
+}
+
+// The MQTT client pushing update notices to devices, see `mqtt.rs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Mqtt {
+    pub enabled: bool,
+    // The broker, connected to over plain TCP.
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    // Credentials for the broker. Anonymous if the username is empty.
+    pub username: String,
+    pub password: String,
+    // First level of every topic rota publishes or subscribes to.
+    pub topic_prefix: String,
+    // Record the versions devices report on `<prefix>/devices/<MAC>/status`.
+    pub subscribe_status: bool,
+}
+
+impl Default for Mqtt {
+    fn default() -> Mqtt {
+        Mqtt {
+            enabled: false,
+            host: String::from("localhost"),
+            port: 1883,
+            client_id: String::from("rota"),
+            username: String::new(),
+            password: String::new(),
+            topic_prefix: String::from("rota"),
+            subscribe_status: false,
+        }
+    }
+}
+
+// Pushing firmware to devices running ArduinoOTA, see `espota.rs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Espota {
+    // The ArduinoOTA password of the devices. Pushed without authentication if empty.
+    pub password: String,
+    // UDP port devices listen for invitations on, 8266 on the ESP8266 and 3232 on the ESP32.
+    pub port: u16,
+    // TCP port devices connect back to for the firmware. Any free port if 0.
+    pub host_port: u16,
+    // How long to wait for a device to answer, in seconds.
+    pub timeout_secs: u64,
+}
+
+impl Default for Espota {
+    fn default() -> Espota {
+        Espota {
+            password: String::new(),
+            port: 8266,
+            host_port: 0,
+            timeout_secs: 10,
+        }
+    }
+}
+
+// Advertising rota and discovering devices on the LAN, see `mdns.rs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Mdns {
+    pub enabled: bool,
+    // Name of the service instance, shown by service browsers.
+    pub instance_name: String,
+    // Host name the services point to, `<hostname>.local`.
+    pub hostname: String,
+    // Also advertise the HTTP listener as `_http._tcp`.
+    pub advertise_http: bool,
+    // Record devices advertising ArduinoOTA as `_arduino._tcp`.
+    pub discover_devices: bool,
+}
+
+impl Default for Mdns {
+    fn default() -> Mdns {
+        Mdns {
+            enabled: false,
+            instance_name: String::from("rota"),
+            hostname: String::from("rota"),
+            advertise_http: true,
+            discover_devices: false,
+        }
+    }
+}
+
+// The hawkBit Direct Device Integration API, see `ddi.rs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Hawkbit {
+    pub enabled: bool,
+    // The tenant in the DDI paths, `/<tenant>/controller/v1/<controller id>`.
+    pub tenant: String,
+    // How long controllers are told to sleep between polls, in seconds.
+    pub polling_interval_secs: u64,
+}
+
+impl Default for Hawkbit {
+    fn default() -> Hawkbit {
+        Hawkbit {
+            enabled: false,
+            tenant: String::from("DEFAULT"),
+            polling_interval_secs: 300,
+        }
+    }
+}
+
+// The queue of commands for devices, see `commands.rs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Commands {
+    // How long a command waits for its device unless a different expiry is given when it is queued, in seconds.
+    pub expiry_secs: i64,
+    // How long finished commands are kept, in days.
+    pub history_days: i64,
+}
+
+impl Commands {
+    // This function keeps the durations within the range dates can be computed with, so a typo cannot make every
+    // update check fail.
+    fn bound(&mut self) {
+        let expiry_secs = self.expiry_secs.clamp(1, MAX_EXPIRY_SECS);
+        let history_days = self.history_days.clamp(0, MAX_HISTORY_DAYS);
+        if (expiry_secs, history_days) != (self.expiry_secs, self.history_days) {
+            eprintln!("commands.expiry_secs must lie between 1 and {} and commands.history_days between 0 and {}, \
+                       using {} and {}.", MAX_EXPIRY_SECS, MAX_HISTORY_DAYS, expiry_secs, history_days);
+            self.expiry_secs = expiry_secs;
+            self.history_days = history_days;
+        }
+    }
+}
+
+impl Default for Commands {
+    fn default() -> Commands {
+        Commands {
+            expiry_secs: 24 * 60 * 60,
+            history_days: 30,
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use actix_web::http::HeaderMap;
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::settings::SETTINGS;
+
+type HmacSha256 = Hmac<Sha256>;
+
+lazy_static! {
+    // Nonces seen within the clock skew window, keyed by `mac/nonce` and mapped to the request timestamp.
+    static ref SEEN_NONCES: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
+// This function checks whether the request carries any of the signing headers.
+pub fn is_signed(headers: &HeaderMap) -> bool {
+    headers.contains_key("x-rota-signature") || headers.contains_key("x-rota-timestamp") || headers.contains_key("x-rota-nonce")
+}
+
+// This function derives the signing key of a device from the server master key, so no per-device key has to be stored.
+pub fn device_signing_key(mac: &str) -> Option<String> {
+    derive_signing_key(&SETTINGS.request_signing.master_key, mac)
+}
+
+// This function derives the signing key of `mac` from `master`, `None` if no master key is configured.
+fn derive_signing_key(master: &str, mac: &str) -> Option<String> {
+    if master.is_empty() {
+        return None
+    }
+    let mut mac_hmac = HmacSha256::new_varkey(master.as_bytes()).expect("HMAC accepts any key length");
+    mac_hmac.update(mac.to_uppercase().as_bytes());
+    Some(hex::encode(mac_hmac.finalize().into_bytes()))
+}
+
+// This function returns the message a device signs: its MAC, the unix timestamp and the nonce separated by newlines.
+pub fn signing_message(mac: &str, timestamp: &str, nonce: &str) -> String {
+    format!("{}\n{}\n{}", mac.to_uppercase(), timestamp, nonce)
+}
+
+// This function verifies the `x-rota-signature` header, a hex encoded HMAC-SHA256 over `signing_message` with the device
+// signing key, along with the timestamp skew and nonce reuse. Returns a reason code on failure.
+pub fn verify_request(headers: &HeaderMap, mac: &str) -> Result<(), &'static str> {
+    let signing = &SETTINGS.request_signing;
+    let now = Utc::now().timestamp();
+    verify_signature(headers, mac, &signing.master_key, signing.max_clock_skew_secs, now, &mut SEEN_NONCES.lock().unwrap())
+}
+
+// This function verifies a signed request of `mac` at unix time `now`, with the key derived from `master`, the allowed
+// clock skew `window` and the nonces `seen` so far.
+fn verify_signature(headers: &HeaderMap, mac: &str, master: &str, window: i64, now: i64, seen: &mut HashMap<String, i64>)
+    -> Result<(), &'static str> {
+    let key = match derive_signing_key(master, mac) {
+        Some(key) => key,
+        _ => return Err("signing_disabled")
+    };
+    let header = |name: &str| headers.get(name).and_then(|h| h.to_str().ok());
+    let (timestamp, nonce, signature) = match (header("x-rota-timestamp"), header("x-rota-nonce"), header("x-rota-signature")) {
+        (Some(timestamp), Some(nonce), Some(signature)) if !nonce.is_empty() => (timestamp, nonce, signature),
+        _ => return Err("missing_signature_header")
+    };
+    let sent_at: i64 = match timestamp.parse() {
+        Ok(sent_at) => sent_at,
+        _ => return Err("invalid_timestamp")
+    };
+    // The timestamp is not authenticated yet, so extreme values must not overflow.
+    match now.checked_sub(sent_at).and_then(i64::checked_abs) {
+        Some(skew) if skew <= window => {}
+        _ => return Err("clock_skew")
+    }
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        _ => return Err("invalid_signature")
+    };
+    let mut mac_hmac = HmacSha256::new_varkey(key.as_bytes()).expect("HMAC accepts any key length");
+    mac_hmac.update(signing_message(mac, timestamp, nonce).as_bytes());
+    if mac_hmac.verify(&signature).is_err() {
+        return Err("invalid_signature")
+    }
+    // Only remember nonces of correctly signed requests, so forged requests cannot fill the cache.
+    seen.retain(|_, seen_at| (now - *seen_at).abs() <= window);
+    if seen.insert(format!("{}/{}", mac.to_uppercase(), nonce), sent_at).is_some() {
+        return Err("replayed_nonce")
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{derive_signing_key, signing_message, verify_signature, HmacSha256};
+    use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+    use hmac::{Mac, NewMac};
+    use std::collections::HashMap;
+
+    const MASTER: &str = "master-key";
+    const MAC: &str = "aa:bb:cc:dd:ee:ff";
+    const NOW: i64 = 1_790_000_000;
+
+    fn signed(mac: &str, timestamp: &str, nonce: &str, key: &str) -> HeaderMap {
+        let mut mac_hmac = HmacSha256::new_varkey(key.as_bytes()).unwrap();
+        mac_hmac.update(signing_message(mac, timestamp, nonce).as_bytes());
+        let signature = hex::encode(mac_hmac.finalize().into_bytes());
+        let mut headers = HeaderMap::new();
+        for (name, value) in &[("x-rota-timestamp", timestamp), ("x-rota-nonce", nonce), ("x-rota-signature", &signature)] {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_window() {
+        let key = derive_signing_key(MASTER, MAC).unwrap();
+        let verify = |timestamp: &str| {
+            verify_signature(&signed(MAC, timestamp, "nonce", &key), MAC, MASTER, 300, NOW, &mut HashMap::new())
+        };
+        assert_eq!(verify(&(NOW - 300).to_string()), Ok(()));
+        assert_eq!(verify(&(NOW + 300).to_string()), Ok(()));
+        assert_eq!(verify(&(NOW - 301).to_string()), Err("clock_skew"));
+        assert_eq!(verify(&(NOW + 301).to_string()), Err("clock_skew"));
+        // Differences that overflow are refused rather than wrapping into the window.
+        assert_eq!(verify(&i64::MIN.to_string()), Err("clock_skew"));
+        assert_eq!(verify(&i64::MAX.to_string()), Err("clock_skew"));
+        assert_eq!(verify("soon"), Err("invalid_timestamp"));
+    }
+
+    #[test]
+    fn rejects_replayed_nonces() {
+        let key = derive_signing_key(MASTER, MAC).unwrap();
+        let mut seen = HashMap::new();
+        let request = signed(MAC, &NOW.to_string(), "nonce", &key);
+        assert_eq!(verify_signature(&request, MAC, MASTER, 300, NOW, &mut seen), Ok(()));
+        assert_eq!(verify_signature(&request, MAC, MASTER, 300, NOW + 1, &mut seen), Err("replayed_nonce"));
+        // The same nonce of another device is not a replay.
+        let other = "11:22:33:44:55:66";
+        let request = signed(other, &NOW.to_string(), "nonce", &derive_signing_key(MASTER, other).unwrap());
+        assert_eq!(verify_signature(&request, other, MASTER, 300, NOW, &mut seen), Ok(()));
+    }
+
+    #[test]
+    fn records_nonces_only_of_valid_signatures() {
+        let mut seen = HashMap::new();
+        let forged = signed(MAC, &NOW.to_string(), "nonce", "not-the-device-key");
+        assert_eq!(verify_signature(&forged, MAC, MASTER, 300, NOW, &mut seen), Err("invalid_signature"));
+        assert!(seen.is_empty());
+        let request = signed(MAC, &NOW.to_string(), "nonce", &derive_signing_key(MASTER, MAC).unwrap());
+        assert_eq!(verify_signature(&request, MAC, MASTER, 300, NOW, &mut seen), Ok(()));
+        assert_eq!(seen.len(), 1);
+        assert_eq!(verify_signature(&request, MAC, "", 300, NOW, &mut seen), Err("signing_disabled"));
+    }
+}
@@ -0,0 +1,53 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::get_config_path;
+
+// The TOML stores in the configuration directory are read, changed and written back as a whole, so every module
+// serializes those cycles on its store with a lock of its own, held from `load` through `save`.
+
+// This function loads the TOML store `name` from the configuration directory, or an empty store if it does not exist yet.
+pub fn load<T: DeserializeOwned + Default>(name: &str) -> Result<T, Box<dyn Error>> {
+    match std::fs::read_to_string(format!("{}{}", get_config_path(), name)) {
+        Ok(file) => Ok(toml::from_str(&file)?),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+// This function writes the TOML store `name` in the configuration directory, see `write_atomically`.
+pub fn save<T: Serialize>(name: &str, store: &T) -> Result<(), Box<dyn Error>> {
+    write_atomically(&format!("{}{}", get_config_path(), name), &toml::to_string(store)?)?;
+    Ok(())
+}
+
+// This function replaces a file with `contents` by writing a temporary file next to it and renaming it over the old
+// copy, so readers and a crash mid-write only ever see the old or the new file. The new file keeps the permissions of
+// the old one.
+pub fn write_atomically(path: &str, contents: &str) -> std::io::Result<()> {
+    let temporary = format!("{}.{}.tmp", path, std::process::id());
+    let mut file = File::create(&temporary)?;
+    let permissions = match std::fs::metadata(path) {
+        Ok(metadata) => file.set_permissions(metadata.permissions()),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    let written = permissions.and_then(|_| file.write_all(contents.as_bytes())).and_then(|_| file.sync_all());
+    if let Err(e) = written.and_then(|_| std::fs::rename(&temporary, path)) {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(e)
+    }
+    Ok(())
+}
+
+// This function writes a secret to a new file only its owner can read. It fails if the file exists, so an existing key
+// is never replaced.
+pub fn create_private(path: &str, contents: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()
+}
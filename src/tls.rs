@@ -0,0 +1,210 @@
+use actix_rt::net::TcpStream;
+use actix_tls::rustls::{Session, TlsStream};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, NoClientAuth,
+             ResolvesServerCert, RootCertStore, ServerConfig, SignatureScheme};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use x509_parser::extensions::GeneralName;
+
+use crate::settings::SETTINGS;
+use tracing::{error, info};
+
+// Hands out the current certificate to every TLS handshake. The certificate is swapped by `watch_certificates` when the
+// files on disk change, so renewed certificates are picked up without restarting.
+pub struct ReloadingCertResolver {
+    current: RwLock<CertifiedKey>,
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _server_name: Option<webpki::DNSNameRef>, _sigschemes: &[SignatureScheme]) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// This function loads the PEM certificate chain and private key. The key may be PKCS#8 or PKCS#1 (RSA).
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, Box<dyn Error>> {
+    let chain = certs(&mut BufReader::new(File::open(cert_path)?)).map_err(|_| "Error parsing certificate file.")?;
+    if chain.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path).into())
+    }
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?)).map_err(|_| "Error parsing key file.")?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?)).map_err(|_| "Error parsing key file.")?;
+    }
+    let key = match keys.first() {
+        Some(key) => any_supported_type(key).map_err(|_| "Unsupported private key type.")?,
+        _ => return Err(format!("No private key found in {}", key_path).into())
+    };
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+// This function returns the last modification time of the certificate and key files.
+fn modified_times(cert_path: &str, key_path: &str) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(cert_path).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(key_path).and_then(|m| m.modified()).ok()?;
+    Some((cert, key))
+}
+
+// This function polls the certificate and key files and swaps in the new certificate once both parse correctly.
+fn watch_certificates(resolver: Arc<ReloadingCertResolver>, cert_path: String, key_path: String, interval: Duration) {
+    let mut last_seen = modified_times(&cert_path, &key_path);
+    loop {
+        std::thread::sleep(interval);
+        let modified = modified_times(&cert_path, &key_path);
+        if modified.is_none() || modified == last_seen {
+            continue
+        }
+        match load_certified_key(&cert_path, &key_path) {
+            Ok(key) => {
+                *resolver.current.write().unwrap() = key;
+                last_seen = modified;
+                info!(cert_path = %cert_path, "Reloaded TLS certificate.");
+            }
+            // Keep serving the old certificate, the files may be half written. Try again next time round.
+            Err(e) => error!(error = %e, "Error reloading TLS certificate."),
+        }
+    }
+}
+
+// This function builds the rustls configuration for the HTTPS listener and starts watching the certificate for changes.
+pub fn server_config() -> std::io::Result<ServerConfig> {
+    let tls = &SETTINGS.tls;
+    let key = load_certified_key(&tls.cert_path, &tls.key_path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Error loading TLS certificate, {}", e)))?;
+    let resolver = Arc::new(ReloadingCertResolver { current: RwLock::new(key) });
+    if tls.reload_interval_secs > 0 {
+        let watched = resolver.clone();
+        let (cert_path, key_path) = (tls.cert_path.clone(), tls.key_path.clone());
+        let interval = Duration::from_secs(tls.reload_interval_secs);
+        std::thread::spawn(move || watch_certificates(watched, cert_path, key_path, interval));
+    }
+    // With a client CA configured, devices may authenticate with a certificate issued by it. Devices without one can
+    // still connect and fall back to API keys, unless every client is required to present a certificate.
+    let client_auth = if tls.client_ca_path.is_empty() {
+        NoClientAuth::new()
+    } else {
+        let mut roots = RootCertStore::empty();
+        let ca_file = File::open(&tls.client_ca_path)?;
+        let (added, _) = roots.add_pem_file(&mut BufReader::new(ca_file))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Error parsing client CA file."))?;
+        info!(certificates = added, "Loaded client CA certificates for mutual TLS.");
+        if tls.require_client_cert {
+            AllowAnyAuthenticatedClient::new(roots)
+        } else {
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        }
+    };
+    let mut config = ServerConfig::new(client_auth);
+    config.cert_resolver = resolver;
+    Ok(config)
+}
+
+// Information about the TLS connection a request arrived on, attached to every request of the HTTPS listener.
+#[derive(Clone)]
+pub struct TlsConnection {
+    // The verified client certificate, if the device presented one.
+    pub peer_certificate: Option<Certificate>,
+}
+
+impl TlsConnection {
+    // This function captures the connection details once the handshake has completed.
+    pub fn from_stream(stream: &TlsStream<TcpStream>) -> TlsConnection {
+        TlsConnection {
+            peer_certificate: stream.get_ref().1.get_peer_certificates().and_then(|chain| chain.first().cloned()),
+        }
+    }
+}
+
+// This function strips separators from a MAC address or certificate name so `AA:BB:..`, `aa-bb-..` and `aabb..` compare equal.
+fn normalize_mac(value: &str) -> String {
+    value.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_uppercase()
+}
+
+// This function checks that the certificate subject common name or one of its subject alternative names identifies the
+// device with `mac`. DNS names match on their first label, e.g. `aabbccddeeff.devices.example.com`.
+pub fn certificate_matches_mac(cert: &Certificate, mac: &str) -> bool {
+    let mac = normalize_mac(mac);
+    let cert = match x509_parser::parse_x509_certificate(&cert.0) {
+        Ok((_, cert)) => cert,
+        Err(e) => {
+            error!(error = %e, "Error parsing client certificate.");
+            return false
+        }
+    };
+    let mut names: Vec<&str> = cert.subject().iter_common_name().filter_map(|cn| cn.as_str().ok()).collect();
+    if let Some((_, san)) = cert.tbs_certificate.subject_alternative_name() {
+        for name in san.general_names.iter() {
+            match name {
+                GeneralName::DNSName(dns) => names.push(dns.split('.').next().unwrap_or(dns)),
+                GeneralName::URI(uri) => names.push(uri),
+                GeneralName::RFC822Name(name) => names.push(name),
+                _ => {}
+            }
+        }
+    }
+    names.iter().any(|name| normalize_mac(name) == mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::certificate_matches_mac;
+    use rustls::internal::pemfile::certs;
+    use rustls::Certificate;
+
+    // Self-signed with `CN=AA:BB:CC:DD:EE:FF` and no subject alternative names.
+    const CN_CERT: &str = "-----BEGIN CERTIFICATE-----\n\
+        MIIBjjCCATWgAwIBAgIUHhDYL4eCKxIenEdgQswaC9EJC2QwCgYIKoZIzj0EAwIw\n\
+        HDEaMBgGA1UEAwwRQUE6QkI6Q0M6REQ6RUU6RkYwIBcNMjYxMDE4MjMxNDE5WhgP\n\
+        MjEyNjA5MjQyMzE0MTlaMBwxGjAYBgNVBAMMEUFBOkJCOkNDOkREOkVFOkZGMFkw\n\
+        EwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEPOJO9M0LQZpBAPqdzaVKOR5SGwhkyqJE\n\
+        p7iGXEZlFscARFl7xbuXKrpuy3e6rYTnmjvR/ZsOPM485xgyZh2/J6NTMFEwHQYD\n\
+        VR0OBBYEFM0uqbArHxgOGtn3PacTTsfmhBicMB8GA1UdIwQYMBaAFM0uqbArHxgO\n\
+        Gtn3PacTTsfmhBicMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDRwAwRAIg\n\
+        AJdL5J49GibwVgnukxGnQ4u4w7JCulAKxDrAszpbPiUCIAvKaT0jdaCS+1gtf/PQ\n\
+        hCuluStbfIG1l1jWyFUUSTOq\n\
+        -----END CERTIFICATE-----\n";
+    // Self-signed with `CN=sensor` and the alternative names `DNS:aabbccddeeff.devices.example.com` and
+    // `email:11-22-33-44-55-66`.
+    const SAN_CERT: &str = "-----BEGIN CERTIFICATE-----\n\
+        MIIBuzCCAWGgAwIBAgIUcWYGiWy0Q8xIofuB/G0bbrKQQLowCgYIKoZIzj0EAwIw\n\
+        ETEPMA0GA1UEAwwGc2Vuc29yMCAXDTI2MTAxODIzMTQxOVoYDzIxMjYwOTI0MjMx\n\
+        NDE5WjARMQ8wDQYDVQQDDAZzZW5zb3IwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNC\n\
+        AAQ84k70zQtBmkEA+p3NpUo5HlIbCGTKokSnuIZcRmUWxwBEWXvFu5cqum7Ld7qt\n\
+        hOeaO9H9mw48zjznGDJmHb8no4GUMIGRMB0GA1UdDgQWBBTNLqmwKx8YDhrZ9z2n\n\
+        E07H5oQYnDAfBgNVHSMEGDAWgBTNLqmwKx8YDhrZ9z2nE07H5oQYnDAPBgNVHRMB\n\
+        Af8EBTADAQH/MD4GA1UdEQQ3MDWCIGFhYmJjY2RkZWVmZi5kZXZpY2VzLmV4YW1w\n\
+        bGUuY29tgRExMS0yMi0zMy00NC01NS02NjAKBggqhkjOPQQDAgNIADBFAiEAl1hZ\n\
+        i80IHEkWH4ZBoZcpnHZvG+MVZ9wI5pvX5+Q6oQECIHsMTP5ZgHXUcZ9tac0X/Lze\n\
+        H83R5mjlPaxd9pGz8+e7\n\
+        -----END CERTIFICATE-----\n";
+
+    fn certificate(pem: &str) -> Certificate {
+        certs(&mut pem.as_bytes()).unwrap().remove(0)
+    }
+
+    #[test]
+    fn matches_the_common_name() {
+        let cert = certificate(CN_CERT);
+        for mac in &["AA:BB:CC:DD:EE:FF", "aa:bb:cc:dd:ee:ff", "aa-bb-cc-dd-ee-ff", "AABBCCDDEEFF"] {
+            assert!(certificate_matches_mac(&cert, mac), "{}", mac);
+        }
+        assert!(!certificate_matches_mac(&cert, "11:22:33:44:55:66"));
+    }
+
+    #[test]
+    fn matches_subject_alternative_names() {
+        let cert = certificate(SAN_CERT);
+        assert!(certificate_matches_mac(&cert, "AA:BB:CC:DD:EE:FF"));
+        assert!(certificate_matches_mac(&cert, "aabbccddeeff"));
+        assert!(certificate_matches_mac(&cert, "11:22:33:44:55:66"));
+        // Only the first label of a DNS name identifies the device.
+        assert!(!certificate_matches_mac(&cert, "AA:BB:CC:DD:EE:00"));
+        assert!(!certificate_matches_mac(&cert, "aabbccddeeffdevicesexamplecom"));
+        assert!(!certificate_matches_mac(&Certificate(b"not a certificate".to_vec()), "AA:BB:CC:DD:EE:FF"));
+    }
+}
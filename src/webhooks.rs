@@ -0,0 +1,294 @@
+use actix_web::client::Client;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::StreamExt;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::credentials::generate_secret;
+use crate::get_config_path;
+use crate::settings::{Webhook, SETTINGS};
+
+// Deliveries queued or being retried before new events are dropped, so an unreachable endpoint cannot exhaust memory.
+const MAX_PENDING: usize = 1000;
+// The longest wait between two attempts, in seconds.
+const MAX_BACKOFF_SECS: u64 = 3600;
+
+lazy_static! {
+    // Hands deliveries to the webhook thread. `None` until `start` is called.
+    static ref QUEUE: Mutex<Option<UnboundedSender<(usize, Delivery)>>> = Mutex::new(None);
+    // Keeps lines from concurrent deliveries from interleaving.
+    static ref DELIVERY_LOG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+// An event on its way to one webhook.
+pub struct Delivery {
+    pub id: String,
+    pub event: String,
+    pub body: String,
+}
+
+// One delivery attempt, as written to `webhook_deliveries.log`.
+#[derive(Serialize, Deserialize)]
+pub struct DeliveryRecord {
+    pub time: DateTime<Utc>,
+    pub id: String,
+    pub url: String,
+    pub event: String,
+    pub attempt: u32,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub delivered: bool,
+    pub retry_in_secs: Option<u64>,
+}
+
+// This function checks whether a webhook subscribes to an event type.
+fn subscribes(hook: &Webhook, kind: &str) -> bool {
+    !hook.url.is_empty() && (hook.events.is_empty() || hook.events.iter().any(|event| event == kind))
+}
+
+// This function checks whether any webhook subscribes to an event type.
+pub fn wants(kind: &str) -> bool {
+    SETTINGS.webhooks.iter().any(|hook| subscribes(hook, kind))
+}
+
+// This function returns the `x-rota-signature` of a delivery: the hex encoded HMAC-SHA256 with the webhook secret over
+// the unix timestamp sent in `x-rota-timestamp` and the body, separated by a newline.
+pub fn signature(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut hmac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts any key length");
+    hmac.update(format!("{}\n{}", timestamp, body).as_bytes());
+    hex::encode(hmac.finalize().into_bytes())
+}
+
+// This function checks whether a failed delivery is worth retrying. Client errors other than timeouts and rate limits
+// will fail the same way again.
+fn is_retryable(status: Option<u16>) -> bool {
+    match status {
+        Some(status) => status >= 500 || status == 408 || status == 429,
+        _ => true,
+    }
+}
+
+// This function posts a delivery to a webhook until it is accepted with a 2xx status or the attempts run out, waiting
+// twice as long after each failure. Every attempt is passed to `log`. Returns whether the delivery was accepted.
+pub async fn deliver(hook: &Webhook, delivery: &Delivery, log: impl Fn(&DeliveryRecord)) -> bool {
+    let client = Client::build().timeout(Duration::from_secs(hook.timeout_secs)).finish();
+    let max_attempts = hook.max_attempts.max(1);
+    let mut backoff = hook.backoff_secs;
+    for attempt in 1..=max_attempts {
+        let timestamp = Utc::now().timestamp().to_string();
+        let mut request = client.post(hook.url.as_str())
+            .header("content-type", "application/json")
+            .header("user-agent", concat!("rota/", env!("CARGO_PKG_VERSION")))
+            .header("x-rota-event", delivery.event.as_str())
+            .header("x-rota-delivery", delivery.id.as_str())
+            .header("x-rota-timestamp", timestamp.as_str());
+        if !hook.secret.is_empty() {
+            request = request.header("x-rota-signature", signature(&hook.secret, &timestamp, &delivery.body));
+        }
+        let (status, error) = match request.send_body(delivery.body.clone()).await {
+            Ok(response) => (Some(response.status().as_u16()), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let delivered = status.map(|status| (200..300).contains(&status)).unwrap_or(false);
+        let retry = !delivered && attempt < max_attempts && is_retryable(status);
+        log(&DeliveryRecord {
+            time: Utc::now(),
+            id: delivery.id.clone(),
+            url: hook.url.clone(),
+            event: delivery.event.clone(),
+            attempt,
+            status,
+            error,
+            delivered,
+            retry_in_secs: if retry { Some(backoff) } else { None },
+        });
+        if delivered {
+            return true
+        }
+        if !retry {
+            break
+        }
+        actix_rt::time::delay_for(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF_SECS);
+    }
+    false
+}
+
+// This function returns the path of the delivery log.
+fn delivery_log_path() -> String {
+    format!("{}{}", get_config_path(), "webhook_deliveries.log")
+}
+
+// This function appends a delivery attempt to the delivery log, one JSON object per line.
+fn record_attempt(record: &DeliveryRecord) {
+    if record.delivered {
+        info!(url = %record.url, event = %record.event, delivery = %record.id, attempt = record.attempt, "Delivered webhook.");
+    } else {
+        warn!(url = %record.url, event = %record.event, delivery = %record.id, attempt = record.attempt, status = ?record.status,
+              error = ?record.error, retry_in_secs = ?record.retry_in_secs, "Webhook delivery failed.");
+    }
+    let _guard = DELIVERY_LOG_LOCK.lock().unwrap();
+    let result = serde_json::to_string(record).map_err(|e| e.to_string()).and_then(|line| {
+        OpenOptions::new().create(true).append(true).open(delivery_log_path())
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        error!(error = %e, "Error writing webhook delivery log.");
+    }
+}
+
+// This function lists the most recent delivery attempts, oldest first.
+pub fn recent_deliveries(limit: usize) -> Result<Vec<DeliveryRecord>, Box<dyn Error>> {
+    let file = match std::fs::read_to_string(delivery_log_path()) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec!()),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let lines: Vec<&str> = file.lines().filter(|line| !line.is_empty()).collect();
+    let start = lines.len().saturating_sub(limit);
+    Ok(lines[start..].iter().filter_map(|line| serde_json::from_str(line).ok()).collect())
+}
+
+// This function queues an event for every webhook subscribing to its type.
+pub fn dispatch(kind: &str, body: &str) {
+    let queue = QUEUE.lock().unwrap();
+    let sender = match queue.as_ref() {
+        Some(sender) => sender,
+        _ => return
+    };
+    for (index, hook) in SETTINGS.webhooks.iter().enumerate() {
+        if !subscribes(hook, kind) {
+            continue
+        }
+        if PENDING.load(Ordering::SeqCst) >= MAX_PENDING {
+            warn!(url = %hook.url, event = kind, "Webhook queue is full, dropping event.");
+            continue
+        }
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        let delivery = Delivery { id: generate_secret(16), event: kind.to_string(), body: body.to_string() };
+        if sender.unbounded_send((index, delivery)).is_err() {
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+            error!("Webhook thread is gone, dropping event.");
+        }
+    }
+}
+
+// This function starts the thread webhooks are delivered from, if any are configured. Deliveries run concurrently on
+// its own runtime, so a slow endpoint holds up neither requests nor other deliveries.
+pub fn start() {
+    if SETTINGS.webhooks.is_empty() {
+        return
+    }
+    let (sender, mut receiver) = unbounded::<(usize, Delivery)>();
+    *QUEUE.lock().unwrap() = Some(sender);
+    std::thread::spawn(move || {
+        actix_rt::System::new("rota-webhooks").block_on(async move {
+            while let Some((index, delivery)) = receiver.next().await {
+                actix_rt::spawn(async move {
+                    deliver(&SETTINGS.webhooks[index], &delivery, record_attempt).await;
+                    PENDING.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        })
+    });
+    info!(webhooks = SETTINGS.webhooks.len(), "Delivering fleet events to webhooks.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deliver, signature, Delivery, DeliveryRecord};
+    use crate::settings::Webhook;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    // The attempt number, status and outcome of a delivery attempt.
+    type Attempt = (u32, Option<u16>, bool);
+
+    // This function runs a stand-in webhook endpoint answering each request with the next status, and returns its URL
+    // and a handle yielding the raw requests it received.
+    fn stand_in(statuses: Vec<u16>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = vec!();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                write!(reader.get_mut(), "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn header<'a>(request: &'a str, name: &str) -> &'a str {
+        request.lines().find_map(|line| line.strip_prefix(&format!("{}: ", name))).unwrap_or_else(|| panic!("no {} header", name))
+    }
+
+    #[test]
+    fn retries_until_delivered_and_signs_each_attempt() {
+        let (url, endpoint) = stand_in(vec![503, 200]);
+        let hook = Webhook { url, secret: String::from("s3cret"), backoff_secs: 0, ..Webhook::default() };
+        let delivery = Delivery { id: String::from("d1"), event: String::from("registered"), body: String::from(r#"{"type":"registered"}"#) };
+        let body = delivery.body.clone();
+        let attempts: Rc<RefCell<Vec<Attempt>>> = Rc::default();
+        let log = attempts.clone();
+        let delivered = actix_rt::System::new("test").block_on(async move {
+            deliver(&hook, &delivery, |r: &DeliveryRecord| log.borrow_mut().push((r.attempt, r.status, r.delivered))).await
+        });
+        assert!(delivered);
+        assert_eq!(*attempts.borrow(), vec![(1, Some(503), false), (2, Some(200), true)]);
+        for request in endpoint.join().unwrap() {
+            assert_eq!(header(&request, "x-rota-event"), "registered");
+            assert!(request.ends_with(&body));
+            let timestamp = header(&request, "x-rota-timestamp");
+            assert_eq!(header(&request, "x-rota-signature"), signature("s3cret", timestamp, &body));
+        }
+    }
+
+    #[test]
+    fn gives_up_on_client_errors() {
+        let (url, endpoint) = stand_in(vec![404]);
+        let hook = Webhook { url, backoff_secs: 0, ..Webhook::default() };
+        let delivery = Delivery { id: String::from("d2"), event: String::from("rejected"), body: String::from("{}") };
+        let attempts: Rc<RefCell<u32>> = Rc::default();
+        let log = attempts.clone();
+        let delivered = actix_rt::System::new("test").block_on(async move {
+            deliver(&hook, &delivery, |_: &DeliveryRecord| *log.borrow_mut() += 1).await
+        });
+        assert!(!delivered);
+        assert_eq!(*attempts.borrow(), 1);
+        let request = endpoint.join().unwrap().remove(0);
+        assert!(!request.contains("x-rota-signature"));
+    }
+}